@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::error::OrgParseError;
+use crate::org_settings::OrgSettings;
+use crate::parse::emacs_element_at_point;
+use crate::parse::emacs_parse_org_document;
+use crate::parse::get_emacs_version;
+use crate::parse::get_org_mode_version;
+use crate::parse::EmacsElementAtPoint;
+use crate::parse::EmacsOutput;
+use crate::parse::ParseOptions;
+use crate::worker_pool::EmacsWorkerPool;
+use crate::worker_pool::WorkerPoolConfig;
+
+/// Something that can turn an org-mode document into the raw output of `org-element-parse-buffer`.
+#[async_trait]
+pub trait OrgParserBackend: Send + Sync {
+    async fn parse_org_document(
+        &self,
+        file_contents: &str,
+        options: &ParseOptions,
+    ) -> Result<EmacsOutput, OrgParseError>;
+
+    /// Run `org-element-at-point` and `org-element-context` at a zero-based character offset.
+    async fn element_at_point(
+        &self,
+        file_contents: &str,
+        settings: &OrgSettings,
+        offset: usize,
+    ) -> Result<EmacsElementAtPoint, OrgParseError>;
+
+    async fn get_emacs_version(&self) -> Result<String, OrgParseError>;
+
+    async fn get_org_mode_version(&self) -> Result<String, OrgParseError>;
+}
+
+/// Build the backend selected by `ORG_INVESTIGATION_BACKEND` (`emacs`, `replay` or `record`, defaulting to `emacs`). Fixtures are read from and written to `ORG_INVESTIGATION_FIXTURES`, defaulting to `fixtures`.
+pub async fn backend_from_env() -> Result<Arc<dyn OrgParserBackend>, Box<dyn std::error::Error>> {
+    let fixture_directory =
+        std::env::var("ORG_INVESTIGATION_FIXTURES").unwrap_or_else(|_| "fixtures".to_owned());
+    let backend_name =
+        std::env::var("ORG_INVESTIGATION_BACKEND").unwrap_or_else(|_| "emacs".to_owned());
+    match backend_name.as_str() {
+        "emacs" => Ok(Arc::new(EmacsBackend::start().await?)),
+        "replay" => Ok(Arc::new(ReplayBackend::load(fixture_directory)?)),
+        "record" => Ok(Arc::new(
+            RecordingBackend::new(EmacsBackend::start().await?, fixture_directory).await?,
+        )),
+        _ => Err(format!(
+            "Unknown ORG_INVESTIGATION_BACKEND {:?}, expected emacs, replay or record.",
+            backend_name
+        )
+        .into()),
+    }
+}
+
+/// Parse with a real emacs through an `EmacsWorkerPool`.
+pub struct EmacsBackend {
+    pool: EmacsWorkerPool,
+}
+
+impl EmacsBackend {
+    pub fn new(pool: EmacsWorkerPool) -> EmacsBackend {
+        EmacsBackend { pool }
+    }
+
+    /// Create a pool configured from the environment and start all of its workers.
+    pub async fn start() -> Result<EmacsBackend, Box<dyn std::error::Error>> {
+        let pool = EmacsWorkerPool::new(WorkerPoolConfig::from_env()?);
+        pool.warm_up().await?;
+        println!("Started {} emacs workers.", pool.size());
+        Ok(EmacsBackend::new(pool))
+    }
+}
+
+#[async_trait]
+impl OrgParserBackend for EmacsBackend {
+    async fn parse_org_document(
+        &self,
+        file_contents: &str,
+        options: &ParseOptions,
+    ) -> Result<EmacsOutput, OrgParseError> {
+        emacs_parse_org_document(&self.pool, file_contents, options).await
+    }
+
+    async fn element_at_point(
+        &self,
+        file_contents: &str,
+        settings: &OrgSettings,
+        offset: usize,
+    ) -> Result<EmacsElementAtPoint, OrgParseError> {
+        emacs_element_at_point(&self.pool, file_contents, settings, offset).await
+    }
+
+    async fn get_emacs_version(&self) -> Result<String, OrgParseError> {
+        get_emacs_version().await
+    }
+
+    async fn get_org_mode_version(&self) -> Result<String, OrgParseError> {
+        get_org_mode_version().await
+    }
+}
+
+/// A recorded request, stored on disk as `<fixture name>.json`.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+enum Fixture {
+    Parse {
+        input: String,
+        options: ParseOptions,
+        output: EmacsOutput,
+    },
+    ElementAtPoint {
+        input: String,
+        settings: OrgSettings,
+        offset: usize,
+        output: EmacsElementAtPoint,
+    },
+}
+
+/// The versions of emacs and org-mode a set of fixtures was recorded with, stored on disk as `versions.json`.
+#[derive(Serialize, Deserialize)]
+struct FixtureVersions {
+    emacs_version: String,
+    org_mode_version: String,
+}
+
+const VERSIONS_FILE_NAME: &str = "versions.json";
+
+/// Serve parses from fixtures previously captured by a `RecordingBackend`, without needing emacs.
+pub struct ReplayBackend {
+    parses: HashMap<(String, ParseOptions), EmacsOutput>,
+    elements_at_point: HashMap<(String, OrgSettings, usize), EmacsElementAtPoint>,
+    versions: FixtureVersions,
+}
+
+impl ReplayBackend {
+    pub fn load<P>(fixture_directory: P) -> Result<ReplayBackend, Box<dyn std::error::Error>>
+    where
+        P: AsRef<Path>,
+    {
+        let fixture_directory = fixture_directory.as_ref();
+        let mut parses = HashMap::new();
+        let mut elements_at_point = HashMap::new();
+        let mut versions = None;
+        for entry in std::fs::read_dir(fixture_directory)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let contents = std::fs::read_to_string(&path)?;
+            if path
+                .file_name()
+                .is_some_and(|name| name == VERSIONS_FILE_NAME)
+            {
+                versions = Some(serde_json::from_str(&contents)?);
+                continue;
+            }
+            let fixture: Fixture = serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid fixture {}: {}", path.display(), e))?;
+            match fixture {
+                Fixture::Parse {
+                    input,
+                    options,
+                    output,
+                } => {
+                    parses.insert((input, options), output);
+                }
+                Fixture::ElementAtPoint {
+                    input,
+                    settings,
+                    offset,
+                    output,
+                } => {
+                    elements_at_point.insert((input, settings, offset), output);
+                }
+            }
+        }
+        let versions = versions.ok_or(format!(
+            "Fixture directory {} has no {}.",
+            fixture_directory.display(),
+            VERSIONS_FILE_NAME
+        ))?;
+        Ok(ReplayBackend {
+            parses,
+            elements_at_point,
+            versions,
+        })
+    }
+}
+
+#[async_trait]
+impl OrgParserBackend for ReplayBackend {
+    async fn parse_org_document(
+        &self,
+        file_contents: &str,
+        options: &ParseOptions,
+    ) -> Result<EmacsOutput, OrgParseError> {
+        self.parses
+            .get(&(file_contents.to_owned(), options.clone()))
+            .cloned()
+            .ok_or_else(|| OrgParseError::MissingFixture {
+                fixture: fixture_name(&(file_contents, options)),
+            })
+    }
+
+    async fn element_at_point(
+        &self,
+        file_contents: &str,
+        settings: &OrgSettings,
+        offset: usize,
+    ) -> Result<EmacsElementAtPoint, OrgParseError> {
+        self.elements_at_point
+            .get(&(file_contents.to_owned(), settings.clone(), offset))
+            .cloned()
+            .ok_or_else(|| OrgParseError::MissingFixture {
+                fixture: fixture_name(&(file_contents, settings, offset)),
+            })
+    }
+
+    async fn get_emacs_version(&self) -> Result<String, OrgParseError> {
+        Ok(self.versions.emacs_version.clone())
+    }
+
+    async fn get_org_mode_version(&self) -> Result<String, OrgParseError> {
+        Ok(self.versions.org_mode_version.clone())
+    }
+}
+
+/// Pass everything through to another backend, saving each successful parse as a fixture for `ReplayBackend`.
+pub struct RecordingBackend<B> {
+    inner: B,
+    fixture_directory: PathBuf,
+}
+
+impl<B> RecordingBackend<B>
+where
+    B: OrgParserBackend,
+{
+    pub async fn new<P>(
+        inner: B,
+        fixture_directory: P,
+    ) -> Result<RecordingBackend<B>, Box<dyn std::error::Error>>
+    where
+        P: Into<PathBuf>,
+    {
+        let fixture_directory = fixture_directory.into();
+        tokio::fs::create_dir_all(&fixture_directory).await?;
+        let versions = FixtureVersions {
+            emacs_version: inner.get_emacs_version().await?,
+            org_mode_version: inner.get_org_mode_version().await?,
+        };
+        tokio::fs::write(
+            fixture_directory.join(VERSIONS_FILE_NAME),
+            serde_json::to_string_pretty(&versions)?,
+        )
+        .await?;
+        Ok(RecordingBackend {
+            inner,
+            fixture_directory,
+        })
+    }
+
+    async fn record(&self, name: String, fixture: Fixture) -> Result<(), OrgParseError> {
+        let path = self.fixture_directory.join(format!("{}.json", name));
+        let serialized = serde_json::to_string_pretty(&fixture).map_err(|e| OrgParseError::Io {
+            message: e.to_string(),
+        })?;
+        tokio::fs::write(&path, serialized)
+            .await
+            .map_err(|e| OrgParseError::Io {
+                message: format!("Failed to record fixture {}: {}", path.display(), e),
+            })
+    }
+}
+
+#[async_trait]
+impl<B> OrgParserBackend for RecordingBackend<B>
+where
+    B: OrgParserBackend,
+{
+    async fn parse_org_document(
+        &self,
+        file_contents: &str,
+        options: &ParseOptions,
+    ) -> Result<EmacsOutput, OrgParseError> {
+        let output = self
+            .inner
+            .parse_org_document(file_contents, options)
+            .await?;
+        let name = fixture_name(&(file_contents, options));
+        self.record(
+            name,
+            Fixture::Parse {
+                input: file_contents.to_owned(),
+                options: options.clone(),
+                output: output.clone(),
+            },
+        )
+        .await?;
+        Ok(output)
+    }
+
+    async fn element_at_point(
+        &self,
+        file_contents: &str,
+        settings: &OrgSettings,
+        offset: usize,
+    ) -> Result<EmacsElementAtPoint, OrgParseError> {
+        let output = self
+            .inner
+            .element_at_point(file_contents, settings, offset)
+            .await?;
+        let name = fixture_name(&(file_contents, settings, offset));
+        self.record(
+            name,
+            Fixture::ElementAtPoint {
+                input: file_contents.to_owned(),
+                settings: settings.clone(),
+                offset,
+                output: output.clone(),
+            },
+        )
+        .await?;
+        Ok(output)
+    }
+
+    async fn get_emacs_version(&self) -> Result<String, OrgParseError> {
+        self.inner.get_emacs_version().await
+    }
+
+    async fn get_org_mode_version(&self) -> Result<String, OrgParseError> {
+        self.inner.get_org_mode_version().await
+    }
+}
+
+/// The file name (without extension) a request is recorded under, derived from everything that identifies the request.
+fn fixture_name<K>(key: &K) -> String
+where
+    K: Serialize,
+{
+    let key = serde_json::to_string(key).expect("Serializing a fixture key cannot fail.");
+    format!("{:016x}", stable_hash(key.as_bytes()))
+}
+
+/// 64-bit FNV-1a. Unlike `std::hash::DefaultHasher` this is guaranteed not to change between builds, so it is safe to use in file names.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The fixtures checked in with the repository, which tests replay instead of running emacs.
+#[cfg(test)]
+pub fn fixture_directory() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_hash_known_values() {
+        assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[tokio::test]
+    async fn replay_fixture() {
+        let backend = ReplayBackend::load(fixture_directory()).expect("Load fixtures");
+        let output = backend
+            .parse_org_document("foo\n\nbar\n", &ParseOptions::default())
+            .await
+            .expect("Fixture exists");
+        assert!(output.ast.starts_with("(org-data"));
+        assert!(!backend
+            .get_emacs_version()
+            .await
+            .expect("Version is recorded")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_missing_fixture() {
+        let backend = ReplayBackend::load(fixture_directory()).expect("Load fixtures");
+        let err = backend
+            .parse_org_document("not recorded\n", &ParseOptions::default())
+            .await
+            .expect_err("Fixture does not exist");
+        assert!(matches!(err, OrgParseError::MissingFixture { .. }));
+    }
+
+    #[tokio::test]
+    async fn record_then_replay() {
+        let recorded_directory =
+            std::env::temp_dir().join(format!("org-investigation-fixtures-{}", std::process::id()));
+        let replay = ReplayBackend::load(fixture_directory()).expect("Load fixtures");
+        let recorder = RecordingBackend::new(replay, &recorded_directory)
+            .await
+            .expect("Create recorder");
+        let recorded = recorder
+            .parse_org_document("foo\n\nbar\n", &ParseOptions::default())
+            .await
+            .expect("Fixture exists");
+
+        let replay = ReplayBackend::load(&recorded_directory).expect("Load recorded fixtures");
+        let replayed = replay
+            .parse_org_document("foo\n\nbar\n", &ParseOptions::default())
+            .await
+            .expect("Fixture was recorded");
+        assert_eq!(recorded.ast, replayed.ast);
+        std::fs::remove_dir_all(&recorded_directory).expect("Clean up fixtures");
+    }
+
+    /// Overwrites the checked-in fixtures and `versions.json` with fresh recordings of every request the tests replay. This needs emacs on the PATH, so run it with `cargo test record_checked_in_fixtures -- --ignored` whenever the worker script changes what it sends back.
+    #[tokio::test]
+    #[ignore]
+    async fn record_checked_in_fixtures() {
+        let emacs = EmacsBackend::start().await.expect("Start emacs");
+        let recorder = RecordingBackend::new(emacs, fixture_directory())
+            .await
+            .expect("Create recorder");
+        let output = recorder
+            .parse_org_document("foo\n\nbar\n", &ParseOptions::default())
+            .await
+            .expect("Parse document");
+        assert_eq!(
+            output.effective_settings.len(),
+            OrgSettings::VARIABLES.len()
+        );
+        recorder
+            .element_at_point("foo\n\nbar\n", &OrgSettings::default(), 6)
+            .await
+            .expect("Find element at point");
+    }
+}
@@ -1,6 +1,18 @@
+use std::fmt::Display;
+use std::string::FromUtf8Error;
+
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::Json;
+use nom::error::ContextError;
 use nom::error::ErrorKind;
 use nom::error::ParseError;
 use nom::IResult;
+use serde::Serialize;
+
+use crate::line_index::LineIndex;
 
 pub type Res<T, U> = IResult<T, U, CustomError<T>>;
 
@@ -8,10 +20,15 @@ pub type Res<T, U> = IResult<T, U, CustomError<T>>;
 pub enum CustomError<I> {
     MyError(MyError<I>),
     Nom(I, ErrorKind),
+    /// The error happened inside the construct (for example a `list`) that starts at `I`.
+    Context(I, &'static str, Box<CustomError<I>>),
+    /// The construct starting at `I` is nested deeper than the maximum depth.
+    TooDeep(I, usize),
 }
 
+/// Something other than what was expected was found at `I`. The `&str` describes what was expected, for example "closing `\"`".
 #[derive(Debug, PartialEq)]
-pub struct MyError<I>(pub I);
+pub struct MyError<I>(pub I, pub &'static str);
 
 impl<I> ParseError<I> for CustomError<I> {
     fn from_error_kind(input: I, kind: ErrorKind) -> Self {
@@ -23,3 +40,335 @@ impl<I> ParseError<I> for CustomError<I> {
         other
     }
 }
+
+impl<I> ContextError<I> for CustomError<I> {
+    fn add_context(input: I, ctx: &'static str, other: Self) -> Self {
+        CustomError::Context(input, ctx, Box::new(other))
+    }
+}
+
+/// Everything that can go wrong between receiving a document and producing an `OwnerTree`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum OrgParseError {
+    /// The request body could not be read, for example because it is not JSON or is missing a field. `status` is the HTTP status axum chose for it.
+    InvalidRequest { status: u16, message: String },
+    /// The requested `OrgSettings` are not allowed.
+    InvalidSettings { message: String },
+    /// Emacs could not be started.
+    Spawn { message: String },
+    /// We could not talk to a running emacs, or it replied with something we did not understand.
+    Io { message: String },
+    /// Emacs exited while we were waiting on it.
+    NonZeroExit { status: Option<i32> },
+    /// Emacs did not finish within the configured timeout and was killed.
+    Timeout { timeout_ms: u128 },
+    /// An elisp error was signaled while parsing, as caught by `condition-case`.
+    Signal {
+        symbol: String,
+        message: String,
+        data: Vec<String>,
+    },
+    /// Emacs produced output that is not valid UTF-8.
+    InvalidUtf8 { message: String },
+    /// The AST printed by emacs could not be read.
+    ///
+    /// `position` is a byte offset into the AST and `line` and `column` (counted in characters) are one-based. `excerpt` is the offending line with a caret under the error. `context` lists the constructs the error is inside of, outermost first.
+    Sexp {
+        position: usize,
+        line: usize,
+        column: usize,
+        message: String,
+        excerpt: String,
+        context: Vec<SexpContext>,
+    },
+    /// The AST was read but could not be turned into an `OwnerTree`. `path` lists the nodes from the root down to the one that failed.
+    OwnerTree { path: Vec<String>, message: String },
+    /// The AST was read but could not be converted to JSON, for example because a string has an invalid escape.
+    JsonSexp { message: String },
+    /// A position outside the document was requested. Both are counted in characters.
+    InvalidOffset { offset: usize, length: usize },
+    /// The replay backend has no recording of this document and options. `fixture` is the name it would have been recorded under.
+    MissingFixture { fixture: String },
+}
+
+/// A construct that a sexp error happened inside of, such as the list it is an element of.
+#[derive(Debug, Serialize)]
+pub struct SexpContext {
+    pub construct: String,
+    /// Where the construct starts, one-based.
+    pub line: usize,
+    pub column: usize,
+}
+
+/// How many characters of the offending line to show on either side of a sexp error.
+const EXCERPT_RADIUS: usize = 30;
+
+impl OrgParseError {
+    pub fn from_sexp_error(input: &str, err: nom::Err<CustomError<&str>>) -> OrgParseError {
+        let line_index = LineIndex::new(input);
+        let mut err = match err {
+            nom::Err::Incomplete(_) => {
+                return OrgParseError::sexp_in(
+                    input,
+                    &line_index,
+                    input.len(),
+                    "Unexpected end of input.",
+                )
+            }
+            nom::Err::Error(err) | nom::Err::Failure(err) => err,
+        };
+        let mut context = Vec::new();
+        let (remaining, message) = loop {
+            match err {
+                CustomError::Context(start, construct, inner) => {
+                    let (line, column) =
+                        line_index.line_column(line_index.char_offset(input.len() - start.len()));
+                    context.push(SexpContext {
+                        construct: construct.to_owned(),
+                        line,
+                        column,
+                    });
+                    err = *inner;
+                }
+                CustomError::MyError(MyError(remaining, expected)) => {
+                    break (remaining, format!("Expected {}.", expected))
+                }
+                CustomError::Nom(remaining, kind) => {
+                    break (remaining, format!("Unexpected input ({:?}).", kind))
+                }
+                CustomError::TooDeep(remaining, max_depth) => {
+                    break (
+                        remaining,
+                        format!("Nesting exceeds the maximum depth of {}.", max_depth),
+                    )
+                }
+            }
+        };
+        let mut sexp_error =
+            OrgParseError::sexp_in(input, &line_index, input.len() - remaining.len(), message);
+        if let OrgParseError::Sexp {
+            context: sexp_context,
+            ..
+        } = &mut sexp_error
+        {
+            *sexp_context = context;
+        }
+        sexp_error
+    }
+
+    /// A sexp error at byte `position` of `input`, with no enclosing context.
+    pub fn sexp_at<M>(input: &str, position: usize, message: M) -> OrgParseError
+    where
+        M: Into<String>,
+    {
+        OrgParseError::sexp_in(input, &LineIndex::new(input), position, message)
+    }
+
+    /// Like `sexp_at`, reusing an index of `input` that is already built.
+    fn sexp_in<M>(
+        input: &str,
+        line_index: &LineIndex<'_>,
+        position: usize,
+        message: M,
+    ) -> OrgParseError
+    where
+        M: Into<String>,
+    {
+        let (line, column) = line_index.line_column(line_index.char_offset(position));
+        OrgParseError::Sexp {
+            position,
+            line,
+            column,
+            message: message.into(),
+            excerpt: excerpt(input, position),
+            context: Vec::new(),
+        }
+    }
+
+    pub fn owner_tree<E>(err: E) -> OrgParseError
+    where
+        E: Display,
+    {
+        OrgParseError::OwnerTree {
+            path: Vec::new(),
+            message: err.to_string(),
+        }
+    }
+
+    /// Record that this error happened inside the node described by `segment`.
+    pub fn within<S>(mut self, segment: S) -> OrgParseError
+    where
+        S: Into<String>,
+    {
+        if let OrgParseError::OwnerTree { path, .. } = &mut self {
+            path.insert(0, segment.into());
+        }
+        self
+    }
+
+    /// Record that this error happened inside the nodes described by `segments`, outermost first.
+    pub fn within_path<I>(mut self, segments: I) -> OrgParseError
+    where
+        I: IntoIterator<Item = String>,
+    {
+        if let OrgParseError::OwnerTree { path, .. } = &mut self {
+            let mut outer: Vec<String> = segments.into_iter().collect();
+            outer.append(path);
+            *path = outer;
+        }
+        self
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OrgParseError::Spawn { .. }
+            | OrgParseError::Io { .. }
+            | OrgParseError::NonZeroExit { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            OrgParseError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            OrgParseError::MissingFixture { .. } => StatusCode::NOT_FOUND,
+            OrgParseError::InvalidRequest { status, .. } => {
+                StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_REQUEST)
+            }
+            OrgParseError::InvalidSettings { .. }
+            | OrgParseError::InvalidOffset { .. }
+            | OrgParseError::Signal { .. }
+            | OrgParseError::InvalidUtf8 { .. }
+            | OrgParseError::Sexp { .. }
+            | OrgParseError::OwnerTree { .. }
+            | OrgParseError::JsonSexp { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl Display for OrgParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrgParseError::InvalidRequest { message, .. } => {
+                write!(f, "Invalid request: {}", message)
+            }
+            OrgParseError::InvalidSettings { message } => {
+                write!(f, "Invalid org-mode settings: {}", message)
+            }
+            OrgParseError::Spawn { message } => write!(f, "Failed to start emacs: {}", message),
+            OrgParseError::Io { message } => write!(f, "Failed to talk to emacs: {}", message),
+            OrgParseError::NonZeroExit {
+                status: Some(status),
+            } => {
+                write!(f, "Emacs exited with status {}.", status)
+            }
+            OrgParseError::NonZeroExit { status: None } => {
+                write!(f, "Emacs was terminated by a signal.")
+            }
+            OrgParseError::Timeout { timeout_ms } => {
+                write!(
+                    f,
+                    "Emacs did not finish within {} milliseconds.",
+                    timeout_ms
+                )
+            }
+            OrgParseError::Signal {
+                symbol, message, ..
+            } => write!(f, "Emacs signaled {}: {}", symbol, message),
+            OrgParseError::InvalidUtf8 { message } => {
+                write!(f, "Emacs output is not valid UTF-8: {}", message)
+            }
+            OrgParseError::Sexp {
+                line,
+                column,
+                message,
+                excerpt,
+                context,
+                ..
+            } => {
+                write!(
+                    f,
+                    "Failed to read the AST at line {}, column {}: {}",
+                    line, column, message
+                )?;
+                for construct in context {
+                    write!(
+                        f,
+                        "\n  in {} starting at line {}, column {}",
+                        construct.construct, construct.line, construct.column
+                    )?;
+                }
+                write!(f, "\n{}", excerpt)
+            }
+            OrgParseError::OwnerTree { path, message } => write!(
+                f,
+                "Failed to build the owner tree at {}: {}",
+                path.join(" > "),
+                message
+            ),
+            OrgParseError::JsonSexp { message } => {
+                write!(f, "Failed to convert the AST to JSON: {}", message)
+            }
+            OrgParseError::InvalidOffset { offset, length } => write!(
+                f,
+                "Offset {} is outside the document, which is {} characters long.",
+                offset, length
+            ),
+            OrgParseError::MissingFixture { fixture } => {
+                write!(
+                    f,
+                    "No recorded parse for this request (fixture {}).",
+                    fixture
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for OrgParseError {}
+
+/// The line containing byte `position`, cut down to the characters around it, followed by a line with a caret under `position`.
+fn excerpt(input: &str, position: usize) -> String {
+    let line_start = input[..position]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let line_end = input[position..]
+        .find('\n')
+        .map_or(input.len(), |newline| position + newline);
+    let before: Vec<char> = input[line_start..position].chars().collect();
+    let after: Vec<char> = input[position..line_end].chars().collect();
+    let mut excerpt = String::new();
+    let skipped = before.len().saturating_sub(EXCERPT_RADIUS);
+    if skipped > 0 {
+        excerpt.push_str("...");
+    }
+    excerpt.extend(&before[skipped..]);
+    let caret_column = excerpt.chars().count();
+    excerpt.extend(after.iter().take(EXCERPT_RADIUS));
+    if after.len() > EXCERPT_RADIUS {
+        excerpt.push_str("...");
+    }
+    excerpt.push('\n');
+    excerpt.extend(std::iter::repeat_n(' ', caret_column));
+    excerpt.push('^');
+    excerpt
+}
+
+impl From<FromUtf8Error> for OrgParseError {
+    fn from(err: FromUtf8Error) -> Self {
+        OrgParseError::InvalidUtf8 {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// A request body that is not valid JSON or does not match the request type, such as one naming a setting outside the allowlist.
+impl From<JsonRejection> for OrgParseError {
+    fn from(rejection: JsonRejection) -> Self {
+        OrgParseError::InvalidRequest {
+            status: rejection.status().as_u16(),
+            message: rejection.body_text(),
+        }
+    }
+}
+
+impl IntoResponse for OrgParseError {
+    fn into_response(self) -> Response {
+        (self.status_code(), Json(self)).into_response()
+    }
+}
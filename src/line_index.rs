@@ -0,0 +1,143 @@
+/// Looks up the lines of character offsets in a document, which is scanned once up front rather than for every lookup.
+///
+/// Offsets count characters from zero. Lines end at `\n`. A `\r` right before it belongs to the line ending, so CRLF documents have the same lines and columns as LF ones, while a lone `\r` is an ordinary character like it is in emacs.
+pub struct LineIndex<'s> {
+    source: &'s str,
+    /// The character and byte offsets each line starts at.
+    line_starts: Vec<(usize, usize)>,
+    char_count: usize,
+}
+
+impl<'s> LineIndex<'s> {
+    pub fn new(source: &'s str) -> LineIndex<'s> {
+        let mut line_starts = vec![(0, 0)];
+        let mut char_count = 0;
+        for (byte, c) in source.char_indices() {
+            char_count += 1;
+            if c == '\n' {
+                line_starts.push((char_count, byte + 1));
+            }
+        }
+        LineIndex {
+            source,
+            line_starts,
+            char_count,
+        }
+    }
+
+    /// The number of characters in the document.
+    pub fn char_count(&self) -> usize {
+        self.char_count
+    }
+
+    /// The zero-based index of the line holding `offset`. Offsets past the end are on the last line.
+    fn line_index(&self, offset: usize) -> usize {
+        self.line_starts
+            .partition_point(|(start, _)| *start <= offset)
+            - 1
+    }
+
+    /// The one-based line holding `offset`, which is also one more than the number of newlines before it.
+    pub fn line(&self, offset: usize) -> usize {
+        self.line_index(offset) + 1
+    }
+
+    /// The one-based line and column of `offset`, with columns counted in characters.
+    pub fn line_column(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_index(offset);
+        (line + 1, offset - self.line_starts[line].0 + 1)
+    }
+
+    /// Whether `offset` directly follows a `\n`.
+    pub fn is_line_start(&self, offset: usize) -> bool {
+        offset > 0
+            && self
+                .line_starts
+                .binary_search_by_key(&offset, |(start, _)| *start)
+                .is_ok()
+    }
+
+    /// The offset of the first character of the line holding `offset`.
+    pub fn line_start(&self, offset: usize) -> usize {
+        self.line_starts[self.line_index(offset)].0
+    }
+
+    /// The offset of the first character of the line after the one holding `offset`, if there is one.
+    pub fn next_line_start(&self, offset: usize) -> Option<usize> {
+        self.line_starts
+            .get(self.line_index(offset) + 1)
+            .map(|(start, _)| *start)
+    }
+
+    /// The offset just past the last character of the line holding `offset`, before its `\n` or `\r\n`.
+    pub fn line_end(&self, offset: usize) -> usize {
+        match self.line_starts.get(self.line_index(offset) + 1) {
+            Some((start, byte)) if self.source[..byte - 1].ends_with('\r') => start - 2,
+            Some((start, _)) => start - 1,
+            None => self.char_count,
+        }
+    }
+
+    /// The byte offset of character `offset`, found by scanning only the line it is on. Offsets past the end give the length of the document.
+    pub fn byte_offset(&self, offset: usize) -> usize {
+        let (start, byte) = self.line_starts[self.line_index(offset)];
+        let line = &self.source[byte..];
+        byte + line
+            .char_indices()
+            .nth(offset - start)
+            .map_or(line.len(), |(index, _)| index)
+    }
+
+    /// The character offset of byte `byte`, which has to be on a character boundary.
+    pub fn char_offset(&self, byte: usize) -> usize {
+        let (start, line_byte) = self.line_starts[self
+            .line_starts
+            .partition_point(|(_, start)| *start <= byte)
+            - 1];
+        start + self.source[line_byte..byte].chars().count()
+    }
+
+    /// The text from character `begin` up to, but not including, character `end`.
+    pub fn slice(&self, begin: usize, end: usize) -> &'s str {
+        &self.source[self.byte_offset(begin)..self.byte_offset(end)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_and_columns() {
+        let index = LineIndex::new("ab\nπc\n\nd");
+        assert_eq!(index.char_count(), 8);
+        assert_eq!(
+            (0..9).map(|offset| index.line(offset)).collect::<Vec<_>>(),
+            [1, 1, 1, 2, 2, 2, 3, 4, 4]
+        );
+        assert_eq!(index.line_column(4), (2, 2));
+        assert_eq!(index.line_column(7), (4, 1));
+        assert!(index.is_line_start(3));
+        assert!(!index.is_line_start(0));
+        assert!(!index.is_line_start(4));
+        assert_eq!(index.line_start(5), 3);
+        assert_eq!(index.next_line_start(5), Some(6));
+        assert_eq!(index.next_line_start(7), None);
+        assert_eq!(index.line_end(3), 5);
+        assert_eq!(index.line_end(7), 8);
+        assert_eq!(index.slice(3, 5), "πc");
+        assert_eq!(index.slice(4, 20), "c\n\nd");
+    }
+
+    #[test]
+    fn crlf() {
+        let index = LineIndex::new("ab\r\nc\rd\r\n");
+        assert_eq!(index.line(4), 2);
+        assert_eq!(index.line_column(6), (2, 3));
+        assert_eq!(index.line_column(index.char_offset(9)), (3, 1));
+        assert_eq!(index.line_end(0), 2);
+        assert_eq!(index.line_end(4), 7);
+        assert_eq!(index.slice(4, index.line_end(4)), "c\rd");
+        assert_eq!(index.next_line_start(4), Some(9));
+    }
+}
@@ -1,21 +1,47 @@
 #![feature(exit_status_error)]
+use std::sync::Arc;
+
+use axum::extract::rejection::JsonRejection;
+use axum::extract::Query;
+use axum::extract::State;
 use axum::http::header::CACHE_CONTROL;
 use axum::http::HeaderValue;
 use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
 use axum::{http::StatusCode, routing::post, Json, Router};
+use backend::backend_from_env;
+use backend::OrgParserBackend;
+use org_settings::OrgSettings;
+use owner_tree::build_element_at_point;
 use owner_tree::build_owner_tree;
-use parse::{emacs_parse_org_document, get_emacs_version};
+use owner_tree::ElementAtPoint;
+use owner_tree::OwnerTree;
+use parse::{EmacsOutput, ExportBackend, Granularity, ParseOptions};
+use parse_cache::CacheKey;
+use parse_cache::ParseCache;
+use serde::Deserialize;
+use sexp::max_depth_from_env;
+use sexp_json::build_json_sexp_tree;
+use sexp_json::JsonSexpTree;
 use tower::ServiceBuilder;
 use tower_http::services::{ServeDir, ServeFile};
 use tower_http::set_header::SetResponseHeaderLayer;
 
-use crate::parse::get_org_mode_version;
+use crate::error::OrgParseError;
 
+mod backend;
 mod error;
+mod line_index;
+mod org_settings;
 mod owner_tree;
 mod parse;
+mod parse_cache;
 mod rtrim_iterator;
 mod sexp;
+mod sexp_json;
+mod sexp_print;
+mod worker_pool;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -30,14 +56,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ))
             .service(serve_dir)
     };
+    let backend = backend_from_env().await?;
+    let (emacs_version, org_mode_version) =
+        tokio::join!(backend.get_emacs_version(), backend.get_org_mode_version());
+    let (emacs_version, org_mode_version) = (emacs_version?, org_mode_version?);
+    println!("Using emacs version: {}", emacs_version.trim());
+    println!("Using org-mode version: {}", org_mode_version.trim());
+
+    let cache = ParseCache::from_env().await?;
+    println!("Caching up to {} parses.", cache.stats().capacity);
+    let max_depth = max_depth_from_env()?;
+    let state = AppState {
+        backend,
+        cache: Arc::new(cache),
+        emacs_version,
+        org_mode_version,
+        max_depth,
+    };
+
     let app = Router::new()
         .route("/parse", post(parse_org_mode))
-        .fallback_service(static_files_service);
-
-    let (emacs_version, org_mode_version) =
-        tokio::join!(get_emacs_version(), get_org_mode_version());
-    println!("Using emacs version: {}", emacs_version?.trim());
-    println!("Using org-mode version: {}", org_mode_version?.trim());
+        .route("/element-at-point", post(element_at_point))
+        .route("/cache", get(cache_stats))
+        .fallback_service(static_files_service)
+        .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
     println!("Listening on port 3000. Pop open your browser to http://127.0.0.1:3000/ .");
@@ -45,14 +87,287 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn parse_org_mode(body: String) -> Result<impl IntoResponse, (StatusCode, String)> {
-    _parse_org_mode(body)
-        .await
-        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+#[derive(Clone)]
+struct AppState {
+    backend: Arc<dyn OrgParserBackend>,
+    cache: Arc<ParseCache>,
+    /// Recorded at startup so cache entries from a different emacs or org-mode are never served.
+    emacs_version: String,
+    org_mode_version: String,
+    /// How deeply nested an AST may be before it is rejected instead of read.
+    max_depth: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParseRequest {
+    input: String,
+    #[serde(default)]
+    settings: OrgSettings,
+    #[serde(default)]
+    granularity: Granularity,
+    #[serde(default)]
+    visible_only: bool,
+    #[serde(default)]
+    export_backends: Vec<ExportBackend>,
+    /// Always ask emacs, neither reading from nor writing to the parse cache.
+    #[serde(default)]
+    bypass_cache: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ParseQuery {
+    #[serde(default)]
+    format: ParseFormat,
+}
+
+/// The shape of the `/parse` response.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum ParseFormat {
+    /// The `OwnerTree` the web interface displays.
+    #[default]
+    OwnerTree,
+    /// The complete AST converted to JSON by `sexp_json::token_to_json`.
+    JsonSexp,
+}
+
+async fn parse_org_mode(
+    State(state): State<AppState>,
+    Query(query): Query<ParseQuery>,
+    request: Result<Json<ParseRequest>, JsonRejection>,
+) -> Result<Response, OrgParseError> {
+    let Json(request) = request?;
+    Ok(match query.format {
+        ParseFormat::OwnerTree => {
+            let owner_tree = _parse_org_mode(&state, request).await?;
+            (StatusCode::OK, Json(owner_tree)).into_response()
+        }
+        ParseFormat::JsonSexp => {
+            let json_sexp_tree = _parse_org_mode_json_sexp(&state, request).await?;
+            (StatusCode::OK, Json(json_sexp_tree)).into_response()
+        }
+    })
+}
+
+async fn _parse_org_mode(
+    state: &AppState,
+    request: ParseRequest,
+) -> Result<OwnerTree, OrgParseError> {
+    let (input, emacs_output) = emacs_output(state, request).await?;
+    build_owner_tree(input.as_str(), &emacs_output, state.max_depth)
+}
+
+async fn _parse_org_mode_json_sexp(
+    state: &AppState,
+    request: ParseRequest,
+) -> Result<JsonSexpTree, OrgParseError> {
+    let (input, emacs_output) = emacs_output(state, request).await?;
+    build_json_sexp_tree(input.as_str(), &emacs_output, state.max_depth)
+}
+
+/// Parse the requested document with the backend, going through the parse cache unless the request bypasses it.
+async fn emacs_output(
+    state: &AppState,
+    request: ParseRequest,
+) -> Result<(String, EmacsOutput), OrgParseError> {
+    let options = ParseOptions {
+        settings: request.settings,
+        granularity: request.granularity,
+        visible_only: request.visible_only,
+        export_backends: request.export_backends,
+    };
+    if request.bypass_cache {
+        let emacs_output = state
+            .backend
+            .parse_org_document(&request.input, &options)
+            .await?;
+        return Ok((request.input, emacs_output));
+    }
+    let key = CacheKey {
+        document: request.input,
+        options,
+        emacs_version: state.emacs_version.clone(),
+        org_mode_version: state.org_mode_version.clone(),
+    };
+    let emacs_output = match state.cache.get(&key) {
+        Some(emacs_output) => emacs_output,
+        None => {
+            let emacs_output = state
+                .backend
+                .parse_org_document(&key.document, &key.options)
+                .await?;
+            state.cache.insert(key.clone(), emacs_output.clone()).await;
+            emacs_output
+        }
+    };
+    Ok((key.document, emacs_output))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ElementAtPointRequest {
+    input: String,
+    #[serde(default)]
+    settings: OrgSettings,
+    /// Zero-based, counted in characters rather than bytes.
+    offset: usize,
+}
+
+async fn element_at_point(
+    State(state): State<AppState>,
+    request: Result<Json<ElementAtPointRequest>, JsonRejection>,
+) -> Result<impl IntoResponse, OrgParseError> {
+    let Json(request) = request?;
+    let element_at_point = _element_at_point(&state, request).await?;
+    Ok((StatusCode::OK, Json(element_at_point)))
+}
+
+async fn _element_at_point(
+    state: &AppState,
+    request: ElementAtPointRequest,
+) -> Result<ElementAtPoint, OrgParseError> {
+    let emacs_output = state
+        .backend
+        .element_at_point(&request.input, &request.settings, request.offset)
+        .await?;
+    build_element_at_point(
+        request.input.as_str(),
+        request.offset,
+        &emacs_output,
+        state.max_depth,
+    )
+}
+
+async fn cache_stats(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.cache.stats())
 }
 
-async fn _parse_org_mode(body: String) -> Result<impl IntoResponse, Box<dyn std::error::Error>> {
-    let ast = emacs_parse_org_document(&body).await?;
-    let owner_tree = build_owner_tree(body.as_str(), ast.as_str()).map_err(|e| e.to_string())?;
-    Ok((StatusCode::OK, Json(owner_tree)))
+#[cfg(test)]
+mod tests {
+    use axum::extract::FromRequest;
+
+    use super::*;
+    use crate::backend::fixture_directory;
+    use crate::backend::ReplayBackend;
+
+    fn replay_state() -> AppState {
+        let backend = ReplayBackend::load(fixture_directory()).expect("Load");
+        AppState {
+            backend: Arc::new(backend),
+            cache: Arc::new(ParseCache::new(8, None)),
+            emacs_version: "GNU Emacs 29.1".to_owned(),
+            org_mode_version: "9.6.6".to_owned(),
+            max_depth: sexp::DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    fn request(input: &str, bypass_cache: bool) -> ParseRequest {
+        ParseRequest {
+            input: input.to_owned(),
+            settings: OrgSettings::default(),
+            granularity: Granularity::default(),
+            visible_only: false,
+            export_backends: Vec::new(),
+            bypass_cache,
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_replayed_document() {
+        let state = replay_state();
+        let owner_tree = _parse_org_mode(&state, request("foo\n\nbar\n", false))
+            .await
+            .expect("Parse recorded document");
+        let owner_tree = serde_json::to_value(owner_tree).expect("Serialize");
+        let section = &owner_tree["tree"]["children"][0];
+        assert_eq!(section["name"], "section");
+        assert_eq!(section["children"][1]["position"]["start_line"], 3);
+    }
+
+    #[tokio::test]
+    async fn parse_cache() {
+        let state = replay_state();
+        for _ in 0..3 {
+            _parse_org_mode(&state, request("foo\n\nbar\n", false))
+                .await
+                .expect("Parse recorded document");
+        }
+        _parse_org_mode(&state, request("foo\n\nbar\n", true))
+            .await
+            .expect("Parse recorded document");
+        let stats = state.cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn parse_replayed_document_as_json_sexp() {
+        let state = replay_state();
+        let json_sexp_tree = _parse_org_mode_json_sexp(&state, request("foo\n\nbar\n", false))
+            .await
+            .expect("Parse recorded document");
+        let json_sexp_tree = serde_json::to_value(json_sexp_tree).expect("Serialize");
+        let org_data = &json_sexp_tree["ast"]["elements"];
+        assert_eq!(org_data[0]["name"], "org-data");
+        let section = &org_data[2]["elements"];
+        assert_eq!(section[0]["name"], "section");
+        assert_eq!(section[1]["type"], "plist");
+        assert_eq!(section[1]["properties"][0]["key"], ":standard-properties");
+    }
+
+    #[tokio::test]
+    async fn malformed_request_is_a_structured_error() {
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/parse")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(
+                r#"{"input": "foo\n", "settings": {"org-startup-folded": "t"}}"#,
+            ))
+            .expect("Build request");
+        let rejection = Json::<ParseRequest>::from_request(request, &())
+            .await
+            .err()
+            .expect("Unknown setting");
+        let error = OrgParseError::from(rejection);
+        assert!(matches!(
+            &error,
+            OrgParseError::InvalidRequest { message, .. } if message.contains("org-startup-folded")
+        ));
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn request_without_json_content_type_keeps_its_status() {
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/parse")
+            .body(axum::body::Body::from(r#"{"input": "foo\n"}"#))
+            .expect("Build request");
+        let rejection = Json::<ParseRequest>::from_request(request, &())
+            .await
+            .err()
+            .expect("Missing content type");
+        let response = OrgParseError::from(rejection).into_response();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn replayed_element_at_point() {
+        let state = replay_state();
+        let request = ElementAtPointRequest {
+            input: "foo\n\nbar\n".to_owned(),
+            settings: OrgSettings::default(),
+            offset: 6,
+        };
+        let element_at_point = _element_at_point(&state, request)
+            .await
+            .expect("Look up recorded position");
+        let element_at_point = serde_json::to_value(element_at_point).expect("Serialize");
+        assert_eq!(element_at_point["at_point"][0]["name"], "org-data");
+        assert_eq!(element_at_point["context"][2]["name"], "paragraph");
+        assert_eq!(element_at_point["context"][2]["position"]["start_line"], 3);
+    }
 }
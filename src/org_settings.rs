@@ -0,0 +1,198 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::parse::escape_elisp_string;
+
+/// Variables that may be bound around a parse, keyed by their elisp name.
+///
+/// Anything not listed here is rejected when deserializing. Unset fields leave the variable at emacs' default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OrgSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_todo_keywords: Option<Vec<TodoKeywordSequence>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_list_allow_alphabetical: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_element_use_cache: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tab_width: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_inlinetask_min_level: Option<u8>,
+}
+
+/// One entry of `org-todo-keywords`, for example `(sequence "TODO" "|" "DONE")`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TodoKeywordSequence {
+    #[serde(rename = "type")]
+    pub sequence_type: TodoKeywordSequenceType,
+    pub keywords: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TodoKeywordSequenceType {
+    Sequence,
+    Type,
+}
+
+impl OrgSettings {
+    /// The elisp names of every variable that can be set, which are also the variables reported back as the effective settings.
+    pub const VARIABLES: &'static [&'static str] = &[
+        "org-todo-keywords",
+        "org-list-allow-alphabetical",
+        "org-element-use-cache",
+        "tab-width",
+        "org-inlinetask-min-level",
+    ];
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(sequences) = &self.org_todo_keywords {
+            for sequence in sequences {
+                if sequence.keywords.is_empty() {
+                    return Err("org-todo-keywords sequences must not be empty.".to_owned());
+                }
+                for keyword in sequence.keywords.iter() {
+                    if keyword.is_empty() || keyword.contains(char::is_whitespace) {
+                        return Err(format!(
+                            "Invalid org-todo-keywords keyword {:?}, keywords must be non-empty and contain no whitespace.",
+                            keyword
+                        ));
+                    }
+                }
+                if sequence.keywords.iter().filter(|k| *k == "|").count() > 1 {
+                    return Err(
+                        "org-todo-keywords sequences may contain at most one \"|\".".to_owned()
+                    );
+                }
+            }
+        }
+        if let Some(tab_width) = self.tab_width {
+            if !(1..=64).contains(&tab_width) {
+                return Err(format!(
+                    "tab-width must be between 1 and 64, got {}.",
+                    tab_width
+                ));
+            }
+        }
+        if let Some(min_level) = self.org_inlinetask_min_level {
+            if min_level < 1 {
+                return Err("org-inlinetask-min-level must be at least 1.".to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Print the set variables as an alist of `(variable . value)` that the worker binds around the parse.
+    ///
+    /// Values are data, not forms, so nothing sent here is ever evaluated.
+    pub fn to_elisp_bindings(&self) -> String {
+        let mut bindings = Vec::new();
+        if let Some(sequences) = &self.org_todo_keywords {
+            let sequences: Vec<String> = sequences
+                .iter()
+                .map(|sequence| {
+                    let sequence_type = match sequence.sequence_type {
+                        TodoKeywordSequenceType::Sequence => "sequence",
+                        TodoKeywordSequenceType::Type => "type",
+                    };
+                    let keywords: Vec<String> = sequence
+                        .keywords
+                        .iter()
+                        .map(|keyword| format!(r#""{}""#, escape_elisp_string(keyword)))
+                        .collect();
+                    format!("({} {})", sequence_type, keywords.join(" "))
+                })
+                .collect();
+            bindings.push(format!("(org-todo-keywords . ({}))", sequences.join(" ")));
+        }
+        if let Some(allow) = self.org_list_allow_alphabetical {
+            bindings.push(format!(
+                "(org-list-allow-alphabetical . {})",
+                elisp_bool(allow)
+            ));
+        }
+        if let Some(use_cache) = self.org_element_use_cache {
+            bindings.push(format!(
+                "(org-element-use-cache . {})",
+                elisp_bool(use_cache)
+            ));
+        }
+        if let Some(tab_width) = self.tab_width {
+            bindings.push(format!("(tab-width . {})", tab_width));
+        }
+        if let Some(min_level) = self.org_inlinetask_min_level {
+            bindings.push(format!("(org-inlinetask-min-level . {})", min_level));
+        }
+        format!("({})", bindings.join(" "))
+    }
+}
+
+fn elisp_bool(value: bool) -> &'static str {
+    if value {
+        "t"
+    } else {
+        "nil"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_bindings() {
+        assert_eq!(OrgSettings::default().to_elisp_bindings(), "()");
+    }
+
+    #[test]
+    fn bindings() {
+        let settings = OrgSettings {
+            org_todo_keywords: Some(vec![
+                TodoKeywordSequence {
+                    sequence_type: TodoKeywordSequenceType::Sequence,
+                    keywords: vec!["TODO".to_owned(), "|".to_owned(), "DONE".to_owned()],
+                },
+                TodoKeywordSequence {
+                    sequence_type: TodoKeywordSequenceType::Type,
+                    keywords: vec![r#"A"B\C"#.to_owned()],
+                },
+            ]),
+            org_list_allow_alphabetical: Some(true),
+            org_element_use_cache: Some(false),
+            tab_width: Some(4),
+            org_inlinetask_min_level: Some(3),
+        };
+        assert_eq!(settings.validate(), Ok(()));
+        assert_eq!(
+            settings.to_elisp_bindings(),
+            r#"((org-todo-keywords . ((sequence "TODO" "|" "DONE") (type "A\"B\\C"))) (org-list-allow-alphabetical . t) (org-element-use-cache . nil) (tab-width . 4) (org-inlinetask-min-level . 3))"#
+        );
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let whitespace = OrgSettings {
+            org_todo_keywords: Some(vec![TodoKeywordSequence {
+                sequence_type: TodoKeywordSequenceType::Sequence,
+                keywords: vec!["NOT DONE".to_owned()],
+            }]),
+            ..Default::default()
+        };
+        assert!(whitespace.validate().is_err());
+        let two_separators = OrgSettings {
+            org_todo_keywords: Some(vec![TodoKeywordSequence {
+                sequence_type: TodoKeywordSequenceType::Sequence,
+                keywords: vec!["|".to_owned(), "TODO".to_owned(), "|".to_owned()],
+            }]),
+            ..Default::default()
+        };
+        assert!(two_separators.validate().is_err());
+        let zero_tab_width = OrgSettings {
+            tab_width: Some(0),
+            ..Default::default()
+        };
+        assert!(zero_tab_width.validate().is_err());
+    }
+}
@@ -1,37 +1,339 @@
+use std::collections::BTreeMap;
+
 use serde::Serialize;
+use serde_json::{json, Value};
 
 use crate::{
-    rtrim_iterator::RTrimIterator,
-    sexp::{sexp_with_padding, Token},
+    error::OrgParseError,
+    line_index::LineIndex,
+    parse::{EmacsElementAtPoint, EmacsOutput, Export},
+    sexp::{read_sexp, Labels, Plist, Span, Token, TokenKind},
+    sexp_json::token_to_json,
+    sexp_print::print_compact,
 };
 
-pub fn build_owner_tree<'a>(
-    body: &'a str,
-    ast_raw: &'a str,
-) -> Result<OwnerTree, Box<dyn std::error::Error + 'a>> {
-    let (_remaining, parsed_sexp) = sexp_with_padding(ast_raw)?;
-    assert_name(&parsed_sexp, "org-data")?;
-    let ast_node = build_ast_node(body, None, &parsed_sexp)?;
+/// Build the tree of nodes the web interface displays. `max_depth` limits how deeply nested the AST may be, both when reading it and when building the tree.
+pub fn build_owner_tree(
+    body: &str,
+    emacs_output: &EmacsOutput,
+    max_depth: usize,
+) -> Result<OwnerTree, OrgParseError> {
+    let ast_raw = emacs_output.ast.as_str();
+    let parsed_sexp = read_sexp(ast_raw, "the AST", max_depth)?;
+    assert_name(&parsed_sexp, "org-data").map_err(OrgParseError::owner_tree)?;
+    let labels = Labels::collect(&parsed_sexp).map_err(OrgParseError::owner_tree)?;
+    let line_index = LineIndex::new(body);
+    let ast_node = build_ast_node(&line_index, &labels, &parsed_sexp, max_depth)
+        .map_err(|e| e.within("org-data"))?;
+    let plain_lists = build_plain_lists(&line_index, &ast_node);
+    let lint = emacs_output
+        .lint
+        .iter()
+        .enumerate()
+        .map(|(index, report)| {
+            build_lint_diagnostic(&line_index, report, max_depth)
+                .map_err(|e| e.within(format!("lint[{}]", index)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
     Ok(OwnerTree {
         input: body.to_owned(),
         ast: ast_raw.to_owned(),
         tree: ast_node,
+        plain_lists,
+        warnings: emacs_output.warnings.clone(),
+        settings: emacs_output.effective_settings.clone(),
+        lint,
+        exports: emacs_output.exports.clone(),
+    })
+}
+
+/// A plain list or item whose nested lists and items are still being collected.
+enum OpenListOrItem {
+    List(PlainList),
+    Item(PlainListItem),
+}
+
+/// Collect the plain lists in the tree, walking it with an explicit stack like `build_ast_node` does.
+fn build_plain_lists(line_index: &LineIndex<'_>, tree: &AstNode) -> Vec<PlainList> {
+    let mut plain_lists = Vec::new();
+    let mut open = Vec::new();
+    // `None` marks where the innermost open list or item ends.
+    let mut stack = vec![Some(tree)];
+    while let Some(step) = stack.pop() {
+        let node = match step {
+            Some(node) => node,
+            None => {
+                match open.pop() {
+                    Some(OpenListOrItem::List(list)) => match open.last_mut() {
+                        Some(OpenListOrItem::Item(item)) => item.lists.push(list),
+                        _ => plain_lists.push(list),
+                    },
+                    Some(OpenListOrItem::Item(item)) => {
+                        // Org never puts an item anywhere but directly inside a plain list.
+                        if let Some(OpenListOrItem::List(list)) = open.last_mut() {
+                            list.items.push(item);
+                        }
+                    }
+                    None => unreachable!("Every list and item is closed once."),
+                }
+                continue;
+            }
+        };
+        match node.name.as_str() {
+            "plain-list" => {
+                open.push(OpenListOrItem::List(PlainList {
+                    position: node.position.clone(),
+                    items: Vec::new(),
+                }));
+                stack.push(None);
+            }
+            "item" => {
+                open.push(OpenListOrItem::Item(build_plain_list_item(
+                    line_index, node,
+                )));
+                stack.push(None);
+            }
+            _ => {}
+        }
+        stack.extend(node.children.iter().rev().map(Some));
+    }
+    plain_lists
+}
+
+fn build_plain_list_item(line_index: &LineIndex<'_>, node: &AstNode) -> PlainListItem {
+    let label = match &node.contents {
+        Some(contents) => {
+            let begin = contents.start_character - 1;
+            // Contents that end before they begin give an empty label rather than a panic.
+            let end = line_index
+                .line_end(begin)
+                .min(contents.end_character - 1)
+                .max(begin);
+            line_index.slice(begin, end).trim().to_owned()
+        }
+        None => String::new(),
+    };
+    let owns_trailing_blank_lines = node.trailing_blank.is_some()
+        || ends_with_blank_line(
+            line_index,
+            node.position.start_character,
+            node.position.end_character,
+        );
+    PlainListItem {
+        position: node.position.clone(),
+        label,
+        owns_trailing_blank_lines,
+        lists: Vec::new(),
+    }
+}
+
+/// Whether the last line from `begin` to `end` holds nothing but whitespace, not counting a first line that is blank.
+fn ends_with_blank_line(line_index: &LineIndex<'_>, begin: usize, end: usize) -> bool {
+    let begin = begin - 1;
+    let mut end = (end - 1).min(line_index.char_count());
+    if end > begin && line_index.is_line_start(end) {
+        end -= 1;
+    }
+    let last_line_begin = line_index.line_start(end);
+    last_line_begin > begin && line_index.slice(last_line_begin, end).trim().is_empty()
+}
+
+/// Read one `org-lint` report printed by the worker. org-lint only reports a single position, so the range runs from there to the end of its line.
+fn build_lint_diagnostic(
+    line_index: &LineIndex<'_>,
+    report_raw: &str,
+    max_depth: usize,
+) -> Result<LintDiagnostic, OrgParseError> {
+    let parsed_sexp = read_sexp(report_raw, "the lint report", max_depth)?;
+    _build_lint_diagnostic(line_index, &parsed_sexp).map_err(OrgParseError::owner_tree)
+}
+
+fn _build_lint_diagnostic<'s>(
+    line_index: &LineIndex<'_>,
+    report: &'s Token<'s>,
+) -> Result<LintDiagnostic, Box<dyn std::error::Error>> {
+    let report = report.as_plist("lint report")?;
+    let get_string = |key: &str| -> Result<String, Box<dyn std::error::Error>> {
+        report.get_string(key)?.ok_or_else(|| report.missing(key))
+    };
+    let begin = report
+        .get_usize(":position")?
+        .ok_or_else(|| report.missing(":position"))?;
+    let end = line_index
+        .next_line_start(begin.checked_sub(1).ok_or("Positions start at 1.")?)
+        .unwrap_or(line_index.char_count())
+        + 1;
+    let (start_line, end_line) = get_line_numbers(line_index, begin, end)?;
+    Ok(LintDiagnostic {
+        checker: get_string(":checker")?,
+        trust: get_string(":trust")?,
+        message: get_string(":message")?,
+        position: SourceRange {
+            start_line,
+            end_line,
+            start_character: begin,
+            end_character: end,
+        },
     })
 }
 
+pub fn build_element_at_point(
+    body: &str,
+    offset: usize,
+    emacs_output: &EmacsElementAtPoint,
+    max_depth: usize,
+) -> Result<ElementAtPoint, OrgParseError> {
+    let line_index = LineIndex::new(body);
+    Ok(ElementAtPoint {
+        input: body.to_owned(),
+        offset,
+        at_point: build_lineage(&line_index, &emacs_output.at_point, max_depth)
+            .map_err(|e| e.within("org-element-at-point"))?,
+        context: build_lineage(&line_index, &emacs_output.context, max_depth)
+            .map_err(|e| e.within("org-element-context"))?,
+        warnings: emacs_output.warnings.clone(),
+    })
+}
+
+/// Read a lineage printed by the worker, innermost first, into nodes ordered from the outermost ancestor down.
+fn build_lineage(
+    line_index: &LineIndex<'_>,
+    lineage_raw: &str,
+    max_depth: usize,
+) -> Result<Vec<AstNode>, OrgParseError> {
+    let parsed_sexp = read_sexp(lineage_raw, "the lineage", max_depth)?;
+    // An empty lineage is printed as nil.
+    if parsed_sexp.as_atom().is_ok_and(|atom| atom == "nil") {
+        return Ok(Vec::new());
+    }
+    let mut lineage = parsed_sexp
+        .as_list()
+        .map_err(OrgParseError::owner_tree)?
+        .iter()
+        .map(|ancestor| {
+            build_childless_ast_node(line_index, None, ancestor)
+                .map_err(|e| OrgParseError::owner_tree(e).within(describe_token(ancestor)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    lineage.reverse();
+    Ok(lineage)
+}
+
 #[derive(Serialize)]
 pub struct OwnerTree {
     input: String,
     ast: String,
     tree: AstNode,
+    /// Every plain list in the document that is not inside another list's item, for studying which items own their trailing blank lines.
+    plain_lists: Vec<PlainList>,
+    warnings: Vec<String>,
+    /// The printed elisp value of each `OrgSettings` variable in effect during the parse.
+    settings: BTreeMap<String, String>,
+    lint: Vec<LintDiagnostic>,
+    /// The document as exported by each requested backend. Export errors are reported here rather than failing the parse.
+    exports: Vec<Export>,
+}
+
+/// A suspicious construct reported by `org-lint`.
+#[derive(Serialize)]
+pub struct LintDiagnostic {
+    /// The name of the org-lint checker, for example `misplaced-planning-info`.
+    checker: String,
+    /// How confident org-lint is that this is a real problem, `high` or `low`.
+    trust: String,
+    message: String,
+    position: SourceRange,
+}
+
+/// What `org-element-at-point` and `org-element-context` found at a position. Each is the chain of ancestors from the outermost down to the element (or object) itself.
+#[derive(Serialize)]
+pub struct ElementAtPoint {
+    input: String,
+    offset: usize,
+    at_point: Vec<AstNode>,
+    context: Vec<AstNode>,
+    warnings: Vec<String>,
 }
 
 #[derive(Serialize)]
 pub struct AstNode {
     name: String,
     position: SourceRange,
+    /// The bytes of the printed AST the node was read from, such as all of `(paragraph (:begin 1 ...) ...)`.
+    span: Span,
+    /// The affiliated keywords, such as `#+NAME:`, before the element proper.
+    affiliated: Option<SourceRange>,
+    /// Where the node's children are parsed from. Everything else in `position` belongs to the node itself.
+    contents: Option<SourceRange>,
+    /// The blank lines after an element, or the spaces and tabs after an object, that `:post-blank` counts as part of it.
+    trailing_blank: Option<SourceRange>,
+    /// Everything else emacs recorded about the node, in the order it was printed. See `OMITTED_PROPERTIES` for what is left out.
+    properties: Vec<Property>,
     children: Vec<AstNode>,
+    /// The node has contents but org did not parse them, which happens when the requested granularity stops above this node.
+    contents_unparsed: bool,
+}
+
+/// An element property with its value converted by `token_to_json`, or an `unreadable` value holding its printed source if it cannot be converted.
+#[derive(Serialize)]
+pub struct Property {
+    key: String,
+    value: Value,
+    /// The run of plain text the property was given for. Element properties cover the whole element and have none.
+    run: Option<SourceRange>,
+}
+
+/// The types in `org-element-all-objects`, whose `:post-blank` counts characters rather than lines.
+const OBJECT_TYPES: [&str; 24] = [
+    "bold",
+    "citation",
+    "citation-reference",
+    "code",
+    "entity",
+    "export-snippet",
+    "footnote-reference",
+    "inline-babel-call",
+    "inline-src-block",
+    "italic",
+    "line-break",
+    "latex-fragment",
+    "link",
+    "macro",
+    "radio-target",
+    "statistics-cookie",
+    "strike-through",
+    "subscript",
+    "superscript",
+    "table-cell",
+    "target",
+    "timestamp",
+    "underline",
+    "verbatim",
+];
+
+/// Properties already described by a node's position, and `:parent`, which only points back up the tree.
+const OMITTED_PROPERTIES: [&str; 10] = [
+    ":begin",
+    ":end",
+    ":contents-begin",
+    ":contents-end",
+    ":post-affiliated",
+    ":post-blank",
+    ":robust-begin",
+    ":robust-end",
+    ":standard-properties",
+    ":parent",
+];
+
+/// Trees can be as deep as the maximum depth allows, so descendants are freed from a flat list rather than recursively.
+impl Drop for AstNode {
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self.children);
+        while let Some(mut node) = stack.pop() {
+            stack.append(&mut node.children);
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -43,10 +345,30 @@ pub struct PlainList {
 #[derive(Serialize)]
 pub struct PlainListItem {
     position: SourceRange,
+    /// The first line of the item's contents, to tell items apart.
+    label: String,
+    /// Whether the item's range ends with blank lines, either counted in its own `:post-blank` or in that of the last element inside it.
+    owns_trailing_blank_lines: bool,
     lists: Vec<PlainList>,
 }
 
-#[derive(Serialize)]
+/// Lists can be nested as deeply as the AST, so nested lists are freed from a flat list rather than recursively.
+impl Drop for PlainList {
+    fn drop(&mut self) {
+        let mut stack: Vec<PlainList> = self
+            .items
+            .iter_mut()
+            .flat_map(|item| std::mem::take(&mut item.lists))
+            .collect();
+        while let Some(mut list) = stack.pop() {
+            for item in list.items.iter_mut() {
+                stack.append(&mut item.lists);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct SourceRange {
     start_line: usize,
     end_line: usize, // Exclusive
@@ -54,8 +376,98 @@ pub struct SourceRange {
     end_character: usize, // Exclusive
 }
 
-fn build_ast_node<'a>(
-    original_source: &str,
+/// A node whose children are still being built.
+struct PendingNode<'p, 's> {
+    token: &'p Token<'s>,
+    node: AstNode,
+    children: std::iter::Enumerate<std::iter::Skip<std::slice::Iter<'p, Token<'s>>>>,
+    /// Where the next plain text child starts. Some nodes don't have a contents begin, so hopefully plain text can't be inside them.
+    contents_begin: Option<usize>,
+    /// How this node appears in error paths, or `None` for the root whose caller describes it.
+    segment: Option<String>,
+}
+
+impl<'p, 's> PendingNode<'p, 's> {
+    fn new(token: &'p Token<'s>, node: AstNode, segment: Option<String>) -> PendingNode<'p, 's> {
+        let parameters = token
+            .as_list()
+            .map_or(&[][..], |parameters| &parameters[..]);
+        PendingNode {
+            token,
+            node,
+            children: parameters.iter().skip(2).enumerate(),
+            contents_begin: get_contents_begin(token).ok(),
+            segment,
+        }
+    }
+}
+
+/// Build the node for a token and all of its descendants, keeping the nodes still being built on an explicit stack so deeply nested trees cannot overflow the call stack.
+fn build_ast_node<'p, 's>(
+    line_index: &LineIndex<'_>,
+    labels: &Labels<'p, 's>,
+    current_token: &'p Token<'s>,
+    max_depth: usize,
+) -> Result<AstNode, OrgParseError> {
+    let root = build_childless_ast_node(line_index, None, current_token)
+        .map_err(OrgParseError::owner_tree)?;
+    let mut stack = vec![PendingNode::new(current_token, root, None)];
+    // Errors name every node from the root down to where they happened.
+    let path = |stack: &[PendingNode<'p, 's>]| -> Vec<String> {
+        stack
+            .iter()
+            .filter_map(|pending| pending.segment.clone())
+            .collect()
+    };
+    loop {
+        let pending = stack
+            .last_mut()
+            .expect("The root is only popped to return it.");
+        if let Some((index, child)) = pending.children.next() {
+            // With print-circle, a child that also appears elsewhere in the tree may be printed as a reference.
+            let child = labels
+                .resolve(child)
+                .map_err(|e| OrgParseError::owner_tree(e).within_path(path(&stack)))?;
+            let segment = format!("{}[{}]", describe_token(child), index);
+            let contents_begin = stack.last().and_then(|pending| pending.contents_begin);
+            let node =
+                build_childless_ast_node(line_index, contents_begin, child).map_err(|e| {
+                    OrgParseError::owner_tree(e)
+                        .within(segment.clone())
+                        .within_path(path(&stack))
+                })?;
+            // References can make the tree cyclic, so this is checked even though the sexp was read with the same limit.
+            if stack.len() >= max_depth {
+                return Err(OrgParseError::owner_tree(format!(
+                    "Nesting exceeds the maximum depth of {}.",
+                    max_depth
+                ))
+                .within(segment)
+                .within_path(path(&stack)));
+            }
+            stack.push(PendingNode::new(child, node, Some(segment)));
+            continue;
+        }
+
+        let PendingNode {
+            token, mut node, ..
+        } = stack.pop().expect("The stack is not empty.");
+        node.contents_unparsed = node.children.is_empty() && has_contents(token).unwrap_or(false);
+        match stack.last_mut() {
+            None => return Ok(node),
+            Some(parent) => {
+                if parent.contents_begin.is_some() {
+                    parent.contents_begin = Some(node.position.end_character);
+                }
+                parent.node.children.push(node);
+            }
+        }
+    }
+}
+
+/// Build the node for a token without descending into its children.
+fn build_childless_ast_node<'a>(
+    line_index: &LineIndex<'_>,
     parent_contents_begin: Option<usize>,
     current_token: &Token<'a>,
 ) -> Result<AstNode, Box<dyn std::error::Error>> {
@@ -64,14 +476,41 @@ fn build_ast_node<'a>(
         Ok(plain_text) => {
             let parent_contents_begin = parent_contents_begin
                 .ok_or("parent_contents_begin should be set for all plain text nodes.")?;
-            let mut parameters = plain_text.properties.iter();
+            let runs: Vec<_> = plain_text.properties.chunks_exact(3).collect();
+            if runs.is_empty() || !plain_text.properties.len().is_multiple_of(3) {
+                return Err("Text properties are not in start, end, plist triples.".into());
+            }
             let begin = parent_contents_begin
-                + maybe_token_to_usize(parameters.next())?
-                    .ok_or("Missing first element past the text.")?;
+                + maybe_token_to_usize(runs.first().map(|run| &run[0]))?
+                    .ok_or("Missing the start of the first text run.")?;
             let end = parent_contents_begin
-                + maybe_token_to_usize(parameters.next())?
-                    .ok_or("Missing second element past the text.")?;
-            let (start_line, end_line) = get_line_numbers(original_source, begin, end)?;
+                + maybe_token_to_usize(runs.last().map(|run| &run[1]))?
+                    .ok_or("Missing the end of the last text run.")?;
+            // Runs can give the same key different values, so each run's properties are listed with the run they cover.
+            let mut properties = Vec::new();
+            for run in runs {
+                if run[2].is_nil() {
+                    continue;
+                }
+                let run_begin = parent_contents_begin
+                    + maybe_token_to_usize(Some(&run[0]))?
+                        .ok_or("Missing the start of a text run.")?;
+                let run_end = parent_contents_begin
+                    + maybe_token_to_usize(Some(&run[1]))?
+                        .ok_or("Missing the end of a text run.")?;
+                let (start_line, end_line) = get_line_numbers(line_index, run_begin, run_end)?;
+                let range = SourceRange {
+                    start_line,
+                    end_line,
+                    start_character: run_begin,
+                    end_character: run_end,
+                };
+                for mut property in get_properties(&run[2].as_plist("plain-text")?)? {
+                    property.run = Some(range.clone());
+                    properties.push(property);
+                }
+            }
+            let (start_line, end_line) = get_line_numbers(line_index, begin, end)?;
             AstNode {
                 name: "plain-text".to_owned(),
                 position: SourceRange {
@@ -80,7 +519,13 @@ fn build_ast_node<'a>(
                     start_character: begin,
                     end_character: end,
                 },
+                span: current_token.unlabeled().span,
+                affiliated: None,
+                contents: None,
+                trailing_blank: None,
+                properties,
                 children: Vec::new(),
+                contents_unparsed: false,
             }
         }
         Err(_) => {
@@ -90,32 +535,25 @@ fn build_ast_node<'a>(
                 .first()
                 .ok_or("Should have at least one child.")?
                 .as_atom()?;
-            let position = get_bounds(original_source, current_token)?;
-            let mut children = Vec::new();
-            let original_contents_begin = get_contents_begin(current_token);
-            match original_contents_begin {
-                Ok(original_contents_begin) => {
-                    let mut contents_begin = original_contents_begin;
-                    for child in parameters.into_iter().skip(2) {
-                        let new_ast_node =
-                            build_ast_node(original_source, Some(contents_begin), child)?;
-                        contents_begin = new_ast_node.position.end_character;
-                        children.push(new_ast_node);
-                    }
-                }
-                Err(_) => {
-                    // Some nodes don't have a contents begin, so hopefully plain text can't be inside them.
-                    for child in parameters.into_iter().skip(2) {
-                        let new_ast_node = build_ast_node(original_source, None, child)?;
-                        children.push(new_ast_node);
-                    }
+            let position = get_bounds(line_index, current_token)?;
+            let regions = get_regions(line_index, name, current_token)?;
+            let properties = match parameters.get(1) {
+                Some(properties) if !properties.is_nil() => {
+                    get_properties(&properties.as_plist(name)?)?
                 }
+                _ => Vec::new(),
             };
 
             AstNode {
                 name: name.to_owned(),
                 position,
-                children,
+                span: current_token.unlabeled().span,
+                affiliated: regions.affiliated,
+                contents: regions.contents,
+                trailing_blank: regions.trailing_blank,
+                properties,
+                children: Vec::new(),
+                contents_unparsed: false,
             }
         }
     };
@@ -123,6 +561,27 @@ fn build_ast_node<'a>(
     Ok(ast_node)
 }
 
+/// A short description of a token for error paths, the element name for lists.
+fn describe_token<'s>(token: &Token<'s>) -> &'s str {
+    match &token.kind {
+        TokenKind::List(children) => children
+            .first()
+            .and_then(|name| name.as_atom().ok())
+            .unwrap_or("list"),
+        TokenKind::TextWithProperties(_) => "plain-text",
+        TokenKind::Vector(_) => "vector",
+        TokenKind::Atom(body) => body,
+        TokenKind::Labeled(_, labeled) => describe_token(labeled),
+        TokenKind::Reference(_) => "reference",
+        TokenKind::DottedList(..) => "dotted-list",
+        TokenKind::Record(_) => "record",
+        TokenKind::ByteCode(_) => "byte-code",
+        TokenKind::BoolVector { .. } => "bool-vector",
+        TokenKind::Character(body) => body,
+        TokenKind::ReaderMacro(..) => "quoted",
+    }
+}
+
 fn assert_name<'s>(emacs: &'s Token<'s>, name: &str) -> Result<(), Box<dyn std::error::Error>> {
     let children = emacs.as_list()?;
     let first_child = children
@@ -140,7 +599,7 @@ fn assert_name<'s>(emacs: &'s Token<'s>, name: &str) -> Result<(), Box<dyn std::
 }
 
 fn get_bounds<'s>(
-    original_source: &'s str,
+    line_index: &LineIndex<'_>,
     emacs: &'s Token<'s>,
 ) -> Result<SourceRange, Box<dyn std::error::Error>> {
     let standard_properties = get_standard_properties(emacs)?;
@@ -150,7 +609,15 @@ fn get_bounds<'s>(
             .ok_or("Token should have a begin.")?,
         standard_properties.end.ok_or("Token should have an end.")?,
     );
-    let (start_line, end_line) = get_line_numbers(original_source, begin, end)?;
+    get_source_range(line_index, begin, end)
+}
+
+fn get_source_range(
+    line_index: &LineIndex<'_>,
+    begin: usize,
+    end: usize,
+) -> Result<SourceRange, Box<dyn std::error::Error>> {
+    let (start_line, end_line) = get_line_numbers(line_index, begin, end)?;
     Ok(SourceRange {
         start_line,
         end_line,
@@ -159,6 +626,74 @@ fn get_bounds<'s>(
     })
 }
 
+/// The parts of an element's `position` that are not the element proper.
+struct Regions {
+    affiliated: Option<SourceRange>,
+    contents: Option<SourceRange>,
+    trailing_blank: Option<SourceRange>,
+}
+
+fn get_regions<'s>(
+    line_index: &LineIndex<'_>,
+    name: &str,
+    emacs: &'s Token<'s>,
+) -> Result<Regions, Box<dyn std::error::Error>> {
+    let standard_properties = get_standard_properties(emacs)?;
+    let begin = standard_properties
+        .begin
+        .ok_or("Token should have a begin.")?;
+    let end = standard_properties.end.ok_or("Token should have an end.")?;
+    let affiliated = match standard_properties.post_affiliated {
+        Some(post_affiliated) if post_affiliated > begin => {
+            Some(get_source_range(line_index, begin, post_affiliated)?)
+        }
+        _ => None,
+    };
+    let contents = match (
+        standard_properties.contents_begin,
+        standard_properties.contents_end,
+    ) {
+        (Some(contents_begin), Some(contents_end)) => {
+            Some(get_source_range(line_index, contents_begin, contents_end)?)
+        }
+        _ => None,
+    };
+    let trailing_blank = match standard_properties.post_blank {
+        Some(post_blank) if post_blank > 0 => {
+            let blank_begin = if OBJECT_TYPES.contains(&name) {
+                end.saturating_sub(post_blank).max(begin)
+            } else {
+                get_trailing_lines_begin(line_index, begin, end, post_blank)
+            };
+            Some(get_source_range(line_index, blank_begin, end)?)
+        }
+        _ => None,
+    };
+    Ok(Regions {
+        affiliated,
+        contents,
+        trailing_blank,
+    })
+}
+
+/// Where the last `lines` lines of the characters from `begin` to `end` start. The final line may lack a newline at the end of the document.
+fn get_trailing_lines_begin(
+    line_index: &LineIndex<'_>,
+    begin: usize,
+    end: usize,
+    lines: usize,
+) -> usize {
+    let begin = begin - 1;
+    let mut line_begin = (end - 1).min(line_index.char_count());
+    for _ in 0..lines {
+        if line_begin > begin && line_index.is_line_start(line_begin) {
+            line_begin -= 1;
+        }
+        line_begin = line_index.line_start(line_begin).max(begin);
+    }
+    line_begin + 1
+}
+
 fn get_contents_begin<'s>(emacs: &'s Token<'s>) -> Result<usize, Box<dyn std::error::Error>> {
     let standard_properties = get_standard_properties(emacs)?;
     Ok(standard_properties
@@ -166,93 +701,138 @@ fn get_contents_begin<'s>(emacs: &'s Token<'s>) -> Result<usize, Box<dyn std::er
         .ok_or("Token should have a contents-begin.")?)
 }
 
-fn get_line_numbers<'s>(
-    original_source: &'s str,
+/// Whether the element has a non-empty contents region, regardless of whether org parsed it.
+fn has_contents<'s>(emacs: &'s Token<'s>) -> Result<bool, Box<dyn std::error::Error>> {
+    let standard_properties = get_standard_properties(emacs)?;
+    Ok(
+        match (
+            standard_properties.contents_begin,
+            standard_properties.contents_end,
+        ) {
+            (Some(contents_begin), Some(contents_end)) => contents_begin < contents_end,
+            _ => false,
+        },
+    )
+}
+
+fn get_line_numbers(
+    line_index: &LineIndex<'_>,
     begin: usize,
     end: usize,
 ) -> Result<(usize, usize), Box<dyn std::error::Error>> {
     // This is used for highlighting which lines contain text relevant to the token, so even if a token does not extend all the way to the end of the line, the end_line figure will be the following line number (since the range is exclusive, not inclusive).
-    let start_line = original_source
-        .chars()
-        .into_iter()
-        .take(usize::try_from(begin)? - 1)
-        .filter(|x| *x == '\n')
-        .count()
-        + 1;
+    let start_line = line_index.line(begin.checked_sub(1).ok_or("Positions start at 1.")?);
     let end_line = {
-        let content_up_to_and_including_token = original_source
-            .chars()
-            .into_iter()
-            .take(usize::try_from(end)? - 1);
+        let up_to_and_including_token = end
+            .checked_sub(1)
+            .ok_or("Positions start at 1.")?
+            .min(line_index.char_count());
         // Remove the trailing newline (if there is one) because we're going to add an extra line regardless of whether or not this ends with a new line.
-        let without_trailing_newline = RTrimIterator::new(content_up_to_and_including_token, '\n');
-        without_trailing_newline.filter(|x| *x == '\n').count() + 2
+        let without_trailing_newline = if line_index.is_line_start(up_to_and_including_token) {
+            up_to_and_including_token - 1
+        } else {
+            up_to_and_including_token
+        };
+        line_index.line(without_trailing_newline) + 1
     };
 
-    Ok((usize::try_from(start_line)?, usize::try_from(end_line)?))
+    Ok((start_line, end_line))
 }
 
 struct StandardProperties {
     begin: Option<usize>,
-    #[allow(dead_code)]
     post_affiliated: Option<usize>,
-    #[allow(dead_code)]
     contents_begin: Option<usize>,
-    #[allow(dead_code)]
     contents_end: Option<usize>,
     end: Option<usize>,
-    #[allow(dead_code)]
     post_blank: Option<usize>,
 }
 
+/// Properties org always gives as a symbol when they are set.
+const SYMBOL_PROPERTIES: [&str; 2] = [":todo-type", ":checkbox"];
+
+/// Properties org always gives as a list when they are set.
+const LIST_PROPERTIES: [&str; 1] = [":tags"];
+
+fn get_properties(properties: &Plist<'_, '_>) -> Result<Vec<Property>, Box<dyn std::error::Error>> {
+    for key in SYMBOL_PROPERTIES {
+        properties.get_symbol(key)?;
+    }
+    for key in LIST_PROPERTIES {
+        properties.get_list(key)?;
+    }
+    let mut decoded = Vec::new();
+    for (key, value) in properties.iter() {
+        let key = key.as_atom()?;
+        if !OMITTED_PROPERTIES.contains(&key) {
+            decoded.push(Property {
+                key: key.to_owned(),
+                value: token_to_json(value).unwrap_or_else(
+                    |_| json!({"type": "unreadable", "source": print_compact(value)}),
+                ),
+                run: None,
+            });
+        }
+    }
+    Ok(decoded)
+}
+
+/// The properties `get_standard_properties` reads, which would be ambiguous if given twice.
+const STANDARD_PROPERTIES: [&str; 7] = [
+    ":standard-properties",
+    ":begin",
+    ":post-affiliated",
+    ":contents-begin",
+    ":contents-end",
+    ":end",
+    ":post-blank",
+];
+
 fn get_standard_properties<'s>(
     emacs: &'s Token<'s>,
 ) -> Result<StandardProperties, Box<dyn std::error::Error>> {
     let children = emacs.as_list()?;
-    let attributes_child = children
-        .iter()
-        .nth(1)
-        .ok_or("Should have an attributes child.")?;
-    let attributes_map = attributes_child.as_map()?;
-    let standard_properties = attributes_map.get(":standard-properties");
-    Ok(if standard_properties.is_some() {
-        let mut std_props = standard_properties
-            .expect("if statement proves its Some")
-            .as_vector()?
-            .into_iter();
-        let begin = maybe_token_to_usize(std_props.next())?;
-        let post_affiliated = maybe_token_to_usize(std_props.next())?;
-        let contents_begin = maybe_token_to_usize(std_props.next())?;
-        let contents_end = maybe_token_to_usize(std_props.next())?;
-        let end = maybe_token_to_usize(std_props.next())?;
-        let post_blank = maybe_token_to_usize(std_props.next())?;
-        StandardProperties {
-            begin,
-            post_affiliated,
-            contents_begin,
-            contents_end,
-            end,
-            post_blank,
-        }
-    } else {
-        let begin = maybe_token_to_usize(attributes_map.get(":begin").map(|token| *token))?;
-        let end = maybe_token_to_usize(attributes_map.get(":end").map(|token| *token))?;
-        let contents_begin =
-            maybe_token_to_usize(attributes_map.get(":contents-begin").map(|token| *token))?;
-        let contents_end =
-            maybe_token_to_usize(attributes_map.get(":contents-end").map(|token| *token))?;
-        let post_blank =
-            maybe_token_to_usize(attributes_map.get(":post-blank").map(|token| *token))?;
-        let post_affiliated =
-            maybe_token_to_usize(attributes_map.get(":post-affiliated").map(|token| *token))?;
-        StandardProperties {
-            begin,
-            post_affiliated,
-            contents_begin,
-            contents_end,
-            end,
-            post_blank,
+    let name = children
+        .first()
+        .ok_or("Should have at least one child.")?
+        .as_atom()?;
+    let properties = children
+        .get(1)
+        .ok_or_else(|| format!("The {} should have an attributes child.", name))?
+        .as_plist(name)?;
+    if let Some(key) = properties
+        .duplicate_keys()
+        .into_iter()
+        .find(|key| STANDARD_PROPERTIES.contains(key))
+    {
+        return Err(format!("The {} has more than one {} property.", name, key).into());
+    }
+    Ok(match properties.get(":standard-properties") {
+        Some(standard_properties) => {
+            let mut std_props = standard_properties.as_vector()?.iter();
+            let begin = maybe_token_to_usize(std_props.next())?;
+            let post_affiliated = maybe_token_to_usize(std_props.next())?;
+            let contents_begin = maybe_token_to_usize(std_props.next())?;
+            let contents_end = maybe_token_to_usize(std_props.next())?;
+            let end = maybe_token_to_usize(std_props.next())?;
+            let post_blank = maybe_token_to_usize(std_props.next())?;
+            StandardProperties {
+                begin,
+                post_affiliated,
+                contents_begin,
+                contents_end,
+                end,
+                post_blank,
+            }
         }
+        None => StandardProperties {
+            begin: properties.get_usize(":begin")?,
+            post_affiliated: properties.get_usize(":post-affiliated")?,
+            contents_begin: properties.get_usize(":contents-begin")?,
+            contents_end: properties.get_usize(":contents-end")?,
+            end: properties.get_usize(":end")?,
+            post_blank: properties.get_usize(":post-blank")?,
+        },
     })
 }
 
@@ -262,13 +842,586 @@ fn maybe_token_to_usize(
     Ok(token
         .map(|token| token.as_atom())
         .map_or(Ok(None), |r| r.map(Some))?
-        .map(|val| {
+        .and_then(|val| {
             if val == "nil" {
                 None
             } else {
                 Some(val.parse::<usize>())
             }
-        })
-        .flatten() // Outer option is whether or not the param exists, inner option is whether or not it is nil
+        }) // Outer option is whether or not the param exists, inner option is whether or not it is nil
         .map_or(Ok(None), |r| r.map(Some))?)
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::rtrim_iterator::RTrimIterator;
+    use crate::sexp::{DEFAULT_MAX_DEPTH, MAX_SAFE_DEPTH};
+
+    fn emacs_output(ast: &str) -> EmacsOutput {
+        EmacsOutput {
+            ast: ast.to_owned(),
+            warnings: Vec::new(),
+            effective_settings: BTreeMap::new(),
+            lint: Vec::new(),
+            exports: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn contents_unparsed() {
+        // What org-element-parse-buffer produces for "foo\n" at element granularity, the paragraph has contents but no plain text child.
+        let ast = "(org-data (:begin 1 :contents-begin 1 :contents-end 5 :end 5) (section (:begin 1 :contents-begin 1 :contents-end 5 :end 5) (paragraph (:begin 1 :contents-begin 1 :contents-end 5 :end 5))))";
+        let owner_tree = build_owner_tree("foo\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        let section = &owner_tree.tree.children[0];
+        let paragraph = &section.children[0];
+        assert!(!owner_tree.tree.contents_unparsed);
+        assert!(!section.contents_unparsed);
+        assert!(paragraph.contents_unparsed);
+        assert!(paragraph.children.is_empty());
+    }
+
+    #[test]
+    fn spans() {
+        let ast = "(org-data (:begin 1 :contents-begin 1 :contents-end 5 :end 5) #1=(paragraph (:begin 1 :contents-begin 1 :contents-end 5 :end 5 :parent nil) #(\"foo\" 0 3 (:parent #1#))))";
+        let owner_tree = build_owner_tree("foo\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        let source = |node: &AstNode| &ast[node.span.start..node.span.end];
+        assert_eq!(source(&owner_tree.tree), ast);
+        let paragraph = &owner_tree.tree.children[0];
+        assert!(source(paragraph).starts_with("(paragraph (:begin 1 "));
+        assert!(source(paragraph).ends_with("(:parent #1#)))"));
+        assert_eq!(
+            source(&paragraph.children[0]),
+            "#(\"foo\" 0 3 (:parent #1#))"
+        );
+    }
+
+    #[test]
+    fn properties() {
+        let ast = r#"(org-data (:begin 1 :contents-begin 1 :contents-end 18 :end 18) #1=(headline (:raw-value "Foo" :begin 1 :end 18 :contents-begin nil :level 1 :tags ("tag") :todo-keyword #("TODO" 0 4 (face org-todo)) :todo-type todo :title (#("Foo" 0 3 (:parent #1#))) :post-blank 0 :parent nil)))"#;
+        let owner_tree =
+            build_owner_tree("* TODO Foo :tag:\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+                .expect("Build owner tree");
+        assert!(owner_tree.tree.properties.is_empty());
+        let headline = serde_json::to_value(&owner_tree.tree.children[0]).expect("Serialize");
+        let properties = headline["properties"].as_array().expect("Properties");
+        let keys: Vec<_> = properties
+            .iter()
+            .map(|property| property["key"].as_str().expect("Key"))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ":raw-value",
+                ":level",
+                ":tags",
+                ":todo-keyword",
+                ":todo-type",
+                ":title"
+            ]
+        );
+        assert_eq!(properties[1]["value"]["value"], 1);
+        assert_eq!(properties[2]["value"]["elements"][0]["value"], "tag");
+        assert_eq!(properties[3]["value"]["value"], "TODO");
+        assert_eq!(properties[4]["value"]["name"], "todo");
+    }
+
+    #[test]
+    fn plain_text_properties() {
+        let ast = r#"(org-data (:begin 1 :contents-begin 1 :contents-end 9 :end 9) (paragraph (:begin 1 :contents-begin 1 :contents-end 9 :end 9) #("foo bar\n" 0 4 (:parent nil face bold) 4 8 (:parent nil face italic :odd #("x" 0 1)))))"#;
+        let owner_tree = build_owner_tree("foo bar\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        let text = &owner_tree.tree.children[0].children[0];
+        assert_eq!(
+            (text.position.start_character, text.position.end_character),
+            (1, 9)
+        );
+        let text = serde_json::to_value(text).expect("Serialize");
+        assert_eq!(
+            text["properties"],
+            serde_json::json!([
+                {
+                    "key": "face",
+                    "value": {"type": "symbol", "name": "bold"},
+                    "run": {"start_line": 1, "end_line": 2, "start_character": 1, "end_character": 5},
+                },
+                {
+                    "key": "face",
+                    "value": {"type": "symbol", "name": "italic"},
+                    "run": {"start_line": 1, "end_line": 2, "start_character": 5, "end_character": 9},
+                },
+                {
+                    "key": ":odd",
+                    "value": {"type": "unreadable", "source": "#(\"x\" 0 1)"},
+                    "run": {"start_line": 1, "end_line": 2, "start_character": 5, "end_character": 9},
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn regions() {
+        let source = "#+NAME: foo\nbar *baz*  qux\n\n\n";
+        let ast = "(org-data (:begin 1 :contents-begin 1 :contents-end 30 :end 30) (section (:begin 1 :contents-begin 1 :contents-end 30 :end 30 :post-blank 0) (paragraph (:begin 1 :post-affiliated 13 :contents-begin 13 :contents-end 28 :end 30 :post-blank 2) #(\"bar \" 0 4 (:parent nil)) (bold (:begin 17 :contents-begin 18 :contents-end 21 :end 24 :post-blank 2) #(\"baz\" 0 3 (:parent nil))) #(\"qux\n\" 0 4 (:parent nil)))))";
+        let owner_tree = build_owner_tree(source, &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        let range = |range: &Option<SourceRange>| {
+            range
+                .as_ref()
+                .map(|range| (range.start_character, range.end_character))
+        };
+        let section = &owner_tree.tree.children[0];
+        assert_eq!(range(&section.affiliated), None);
+        assert_eq!(range(&section.contents), Some((1, 30)));
+        assert_eq!(range(&section.trailing_blank), None);
+        let paragraph = &section.children[0];
+        assert_eq!(range(&paragraph.affiliated), Some((1, 13)));
+        assert_eq!(range(&paragraph.contents), Some((13, 28)));
+        assert_eq!(range(&paragraph.trailing_blank), Some((28, 30)));
+        let trailing_blank = paragraph.trailing_blank.as_ref().expect("Blank lines");
+        assert_eq!((trailing_blank.start_line, trailing_blank.end_line), (3, 5));
+        let bold = &paragraph.children[1];
+        assert_eq!(range(&bold.affiliated), None);
+        assert_eq!(range(&bold.contents), Some((18, 21)));
+        assert_eq!(range(&bold.trailing_blank), Some((22, 24)));
+        assert_eq!(range(&paragraph.children[0].contents), None);
+    }
+
+    #[test]
+    fn plain_list_ownership_test_1() {
+        // Test 1 from notes/plain_list_ownership_notes.org.
+        let source = "1. foo\n\n   1. bar\n\n   2. baz\n\n2. lorem\n\nipsum\n";
+        let ast = "(org-data (:begin 1 :contents-begin 1 :contents-end 47 :end 47) (section (:begin 1 :contents-begin 1 :contents-end 47 :end 47 :post-blank 0) (plain-list (:begin 1 :contents-begin 1 :contents-end 40 :end 41 :post-blank 1) (item (:begin 1 :contents-begin 4 :contents-end 31 :end 31 :post-blank 0) (paragraph (:begin 4 :contents-begin 4 :contents-end 8 :end 9 :post-blank 1)) (plain-list (:begin 9 :contents-begin 9 :contents-end 31 :end 31 :post-blank 0) (item (:begin 9 :contents-begin 15 :contents-end 19 :end 20 :post-blank 1)) (item (:begin 20 :contents-begin 26 :contents-end 30 :end 31 :post-blank 1)))) (item (:begin 31 :contents-begin 34 :contents-end 40 :end 40 :post-blank 0))) (paragraph (:begin 41 :contents-begin 41 :contents-end 47 :end 47 :post-blank 0))))";
+        let owner_tree = build_owner_tree(source, &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        assert_eq!(owner_tree.plain_lists.len(), 1);
+        let ownership = |item: &PlainListItem| (item.label.clone(), item.owns_trailing_blank_lines);
+        let outer = &owner_tree.plain_lists[0].items;
+        let inner = &outer[0].lists[0].items;
+        assert_eq!(
+            [&outer[0], &inner[0], &inner[1], &outer[1]].map(ownership),
+            [
+                ("foo".to_owned(), true),
+                ("bar".to_owned(), true),
+                ("baz".to_owned(), true),
+                ("lorem".to_owned(), false)
+            ]
+        );
+        assert!(outer[1].lists.is_empty());
+    }
+
+    #[test]
+    fn plain_list_ownership_test_2() {
+        // Test 2 from notes/plain_list_ownership_notes.org, where a paragraph after the inner list takes baz's blank lines.
+        let source = "1. foo\n\n   1. bar\n\n   2. baz\n\n   cat\n\n2. lorem\n\nipsum\n";
+        let ast = "(org-data (:begin 1 :contents-begin 1 :contents-end 55 :end 55) (section (:begin 1 :contents-begin 1 :contents-end 55 :end 55 :post-blank 0) (plain-list (:begin 1 :contents-begin 1 :contents-end 48 :end 49 :post-blank 1) (item (:begin 1 :contents-begin 4 :contents-end 39 :end 39 :post-blank 0) (paragraph (:begin 4 :contents-begin 4 :contents-end 8 :end 9 :post-blank 1)) (plain-list (:begin 9 :contents-begin 9 :contents-end 30 :end 31 :post-blank 1) (item (:begin 9 :contents-begin 15 :contents-end 19 :end 20 :post-blank 1)) (item (:begin 20 :contents-begin 26 :contents-end 30 :end 30 :post-blank 0))) (paragraph (:begin 31 :contents-begin 31 :contents-end 38 :end 39 :post-blank 1))) (item (:begin 39 :contents-begin 42 :contents-end 48 :end 48 :post-blank 0))) (paragraph (:begin 49 :contents-begin 49 :contents-end 55 :end 55 :post-blank 0))))";
+        let owner_tree = build_owner_tree(source, &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        let ownership = |item: &PlainListItem| (item.label.clone(), item.owns_trailing_blank_lines);
+        let outer = &owner_tree.plain_lists[0].items;
+        let inner = &outer[0].lists[0].items;
+        assert_eq!(
+            [&outer[0], &inner[0], &inner[1], &outer[1]].map(ownership),
+            [
+                ("foo".to_owned(), true),
+                ("bar".to_owned(), true),
+                ("baz".to_owned(), false),
+                ("lorem".to_owned(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn plain_list_ownership_test_3() {
+        // Test 3 from notes/plain_list_ownership_notes.org, the list from test 1 nested in the only item of another list.
+        let source = "1. cat\n   1. foo\n\n      1. bar\n\n      2. baz\n\n   2. lorem\n\nipsum\n";
+        let ast = "(org-data (:begin 1 :contents-begin 1 :contents-end 66 :end 66) (section (:begin 1 :contents-begin 1 :contents-end 66 :end 66 :post-blank 0) (plain-list (:begin 1 :contents-begin 1 :contents-end 59 :end 60 :post-blank 1) (item (:begin 1 :contents-begin 4 :contents-end 59 :end 59 :post-blank 0) (paragraph (:begin 4 :contents-begin 4 :contents-end 8 :end 8 :post-blank 0)) (plain-list (:begin 8 :contents-begin 8 :contents-end 59 :end 59 :post-blank 0) (item (:begin 8 :contents-begin 14 :contents-end 47 :end 47 :post-blank 0) (paragraph (:begin 14 :contents-begin 14 :contents-end 18 :end 19 :post-blank 1)) (plain-list (:begin 19 :contents-begin 19 :contents-end 47 :end 47 :post-blank 0) (item (:begin 19 :contents-begin 28 :contents-end 32 :end 33 :post-blank 1)) (item (:begin 33 :contents-begin 42 :contents-end 46 :end 47 :post-blank 1)))) (item (:begin 47 :contents-begin 53 :contents-end 59 :end 59 :post-blank 0))))) (paragraph (:begin 60 :contents-begin 60 :contents-end 66 :end 66 :post-blank 0))))";
+        let owner_tree = build_owner_tree(source, &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        let ownership = |item: &PlainListItem| (item.label.clone(), item.owns_trailing_blank_lines);
+        let cat = &owner_tree.plain_lists[0].items[0];
+        let middle = &cat.lists[0].items;
+        let inner = &middle[0].lists[0].items;
+        assert_eq!(
+            [cat, &middle[0], &inner[0], &inner[1], &middle[1]].map(ownership),
+            [
+                ("cat".to_owned(), false),
+                ("foo".to_owned(), true),
+                ("bar".to_owned(), true),
+                ("baz".to_owned(), true),
+                ("lorem".to_owned(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn plain_list_ownership_test_4() {
+        // Test 4 from notes/plain_list_ownership_notes.org, test 3 with a second item after cat.
+        let source =
+            "1. cat\n   1. foo\n\n      1. bar\n\n      2. baz\n\n   2. lorem\n\n2. dog\n\nipsum\n";
+        let ast = "(org-data (:begin 1 :contents-begin 1 :contents-end 74 :end 74) (section (:begin 1 :contents-begin 1 :contents-end 74 :end 74 :post-blank 0) (plain-list (:begin 1 :contents-begin 1 :contents-end 67 :end 68 :post-blank 1) (item (:begin 1 :contents-begin 4 :contents-end 60 :end 60 :post-blank 0) (paragraph (:begin 4 :contents-begin 4 :contents-end 8 :end 8 :post-blank 0)) (plain-list (:begin 8 :contents-begin 8 :contents-end 60 :end 60 :post-blank 0) (item (:begin 8 :contents-begin 14 :contents-end 47 :end 47 :post-blank 0) (paragraph (:begin 14 :contents-begin 14 :contents-end 18 :end 19 :post-blank 1)) (plain-list (:begin 19 :contents-begin 19 :contents-end 47 :end 47 :post-blank 0) (item (:begin 19 :contents-begin 28 :contents-end 32 :end 33 :post-blank 1)) (item (:begin 33 :contents-begin 42 :contents-end 46 :end 47 :post-blank 1)))) (item (:begin 47 :contents-begin 53 :contents-end 59 :end 60 :post-blank 1)))) (item (:begin 60 :contents-begin 63 :contents-end 67 :end 67 :post-blank 0))) (paragraph (:begin 68 :contents-begin 68 :contents-end 74 :end 74 :post-blank 0))))";
+        let owner_tree = build_owner_tree(source, &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        let ownership = |item: &PlainListItem| (item.label.clone(), item.owns_trailing_blank_lines);
+        let outer = &owner_tree.plain_lists[0].items;
+        let middle = &outer[0].lists[0].items;
+        let inner = &middle[0].lists[0].items;
+        assert_eq!(
+            [&outer[0], &middle[0], &inner[0], &inner[1], &middle[1], &outer[1]].map(ownership),
+            [
+                ("cat".to_owned(), true),
+                ("foo".to_owned(), true),
+                ("bar".to_owned(), true),
+                ("baz".to_owned(), true),
+                ("lorem".to_owned(), true),
+                ("dog".to_owned(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn item_contents_ending_before_they_begin() {
+        let ast = "(org-data (:begin 1 :contents-begin 1 :contents-end 7 :end 7) (plain-list (:begin 1 :contents-begin 1 :contents-end 7 :end 7) (item (:begin 1 :contents-begin 4 :contents-end 3 :end 7))))";
+        let owner_tree = build_owner_tree("- foo\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .expect("Build owner tree");
+        assert_eq!(owner_tree.plain_lists[0].items[0].label, "");
+    }
+
+    /// How line numbers were found before `LineIndex`, by scanning the source for every range.
+    fn scanned_line_numbers(source: &str, begin: usize, end: usize) -> (usize, usize) {
+        let start_line = source
+            .chars()
+            .take(begin - 1)
+            .filter(|c| *c == '\n')
+            .count()
+            + 1;
+        let up_to_and_including_token = source.chars().take(end - 1);
+        let end_line = RTrimIterator::new(up_to_and_including_token, '\n')
+            .filter(|c| *c == '\n')
+            .count()
+            + 2;
+        (start_line, end_line)
+    }
+
+    proptest! {
+        #[test]
+        fn line_numbers_match_scanning(source in "[a\n\r π]{0,40}", begin in 1..45usize, length in 0..45usize) {
+            let end = begin + length;
+            let line_index = LineIndex::new(&source);
+            prop_assert_eq!(
+                get_line_numbers(&line_index, begin, end).expect("Line numbers"),
+                scanned_line_numbers(&source, begin, end)
+            );
+        }
+    }
+
+    /// A document of `paragraphs` one line paragraphs separated by blank lines, and the AST emacs would print for it.
+    fn paragraphs(paragraphs: usize) -> (String, String) {
+        let line = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. π\n";
+        let length = line.chars().count();
+        let end = paragraphs * (length + 1) + 1;
+        let mut source = String::new();
+        let mut ast = format!("(org-data (:begin 1 :contents-begin 1 :contents-end {end} :end {end}) (section (:begin 1 :contents-begin 1 :contents-end {end} :end {end} :post-blank 0)");
+        for index in 0..paragraphs {
+            let begin = index * (length + 1) + 1;
+            source.push_str(line);
+            source.push('\n');
+            ast.push_str(&format!(
+                " (paragraph (:begin {} :contents-begin {} :contents-end {} :end {} :post-blank 1) #(\"{}\" 0 {} (:parent nil)))",
+                begin,
+                begin,
+                begin + length,
+                begin + length + 1,
+                line,
+                length
+            ));
+        }
+        ast.push_str("))");
+        (source, ast)
+    }
+
+    /// A benchmark rather than a test, so it only runs when asked for: `cargo test --release build_owner_tree_scales_linearly -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn build_owner_tree_scales_linearly() {
+        let mut timings = Vec::new();
+        for count in [16_384, 65_536] {
+            let (source, ast) = paragraphs(count);
+            let output = emacs_output(&ast);
+            let start = std::time::Instant::now();
+            let owner_tree =
+                build_owner_tree(&source, &output, DEFAULT_MAX_DEPTH).expect("Build owner tree");
+            let elapsed = start.elapsed();
+            assert_eq!(owner_tree.tree.children[0].children.len(), count);
+            println!(
+                "{} byte document with a {} byte AST: {:?}",
+                source.len(),
+                ast.len(),
+                elapsed
+            );
+            timings.push(elapsed);
+        }
+        // Four times the document should take about four times as long, a quadratic builder would take sixteen.
+        let ratio = timings[1].as_secs_f64() / timings[0].as_secs_f64();
+        assert!(ratio < 8.0, "Scaled by {:.1} for 4x the input.", ratio);
+    }
+
+    #[test]
+    fn sexp_error_position() {
+        let ast = "(org-data (:begin 1 :end 5)) )";
+        let err = build_owner_tree("foo\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .err()
+            .expect("Unbalanced list");
+        assert!(matches!(err, OrgParseError::Sexp { position: 29, .. }));
+    }
+
+    #[test]
+    fn sexp_error_context() {
+        let ast = "(org-data\n (:begin 1 :end 5)\n (section [1 2 \"unterminated)))";
+        let err = build_owner_tree("foo\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .err()
+            .expect("Unterminated string");
+        let message = err.to_string();
+        match err {
+            OrgParseError::Sexp {
+                position,
+                line,
+                column,
+                message,
+                excerpt,
+                context,
+            } => {
+                assert_eq!((position, line, column), (60, 3, 32));
+                assert_eq!(message, "Expected closing `\"`.");
+                assert_eq!(
+                    excerpt,
+                    format!("...(section [1 2 \"unterminated)))\n{}^", " ".repeat(33))
+                );
+                let context: Vec<(String, usize, usize)> = context
+                    .into_iter()
+                    .map(|c| (c.construct, c.line, c.column))
+                    .collect();
+                assert_eq!(
+                    context,
+                    vec![
+                        ("list".to_owned(), 1, 1),
+                        ("list".to_owned(), 3, 2),
+                        ("vector".to_owned(), 3, 11)
+                    ]
+                );
+            }
+            _ => panic!("Expected a sexp error, got {:?}", err),
+        }
+        assert!(message.starts_with("Failed to read the AST at line 3, column 32: Expected closing `\"`.\n  in list starting at line 1, column 1\n"));
+    }
+
+    #[test]
+    fn sexp_error_excerpt_is_trimmed() {
+        let ast = format!("(org-data {}] {})", "a ".repeat(50), "b ".repeat(50));
+        let err = build_owner_tree("foo\n", &emacs_output(&ast), DEFAULT_MAX_DEPTH)
+            .err()
+            .expect("Stray bracket");
+        match err {
+            OrgParseError::Sexp {
+                message, excerpt, ..
+            } => {
+                assert_eq!(message, "Expected an expression or `)`.");
+                let (line, caret) = excerpt.split_once('\n').expect("Two lines");
+                assert_eq!(
+                    line,
+                    format!("...{}] {}...", "a ".repeat(15), "b ".repeat(14))
+                );
+                assert_eq!(caret, format!("{}^", " ".repeat(33)));
+            }
+            _ => panic!("Expected a sexp error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn owner_tree_error_path() {
+        let ast = "(org-data (:begin 1 :contents-begin 1 :end 9) (section (:begin 1 :contents-begin 1 :end 9) (paragraph (:begin 1 :contents-begin 1 :end 5)) (paragraph (:begin 5 :contents-begin 5))))";
+        let err = build_owner_tree("foo\nbar\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .err()
+            .expect("Second paragraph has no end");
+        match err {
+            OrgParseError::OwnerTree { path, .. } => {
+                assert_eq!(path, vec!["org-data", "section[0]", "paragraph[1]"])
+            }
+            _ => panic!("Expected an owner tree error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn duplicate_standard_property() {
+        let ast =
+            "(org-data (:begin 1 :contents-begin 1 :end 5) (paragraph (:begin 1 :end 5 :begin 2)))";
+        let err = build_owner_tree("foo\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .err()
+            .expect("Paragraph has two begins");
+        match err {
+            OrgParseError::OwnerTree { path, message } => {
+                assert_eq!(path, ["org-data", "paragraph[0]"]);
+                assert_eq!(message, "The paragraph has more than one :begin property.");
+            }
+            _ => panic!("Expected an owner tree error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn mistyped_property() {
+        let ast = r#"(org-data (:begin 1 :contents-begin 1 :end 5) (headline (:begin 1 :end 5 :todo-type todo :tags "foo")))"#;
+        let err = build_owner_tree("* a\n", &emacs_output(ast), DEFAULT_MAX_DEPTH)
+            .err()
+            .expect("Tags are a string");
+        match err {
+            OrgParseError::OwnerTree { path, message } => {
+                assert_eq!(path, ["org-data", "headline[0]"]);
+                assert_eq!(
+                    message,
+                    "The :tags property of the headline should be a list, got an atom."
+                );
+            }
+            _ => panic!("Expected an owner tree error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn lint_diagnostics() {
+        let body = "foo\n#+BEGIN_SRC\nbar\n";
+        let emacs_output = EmacsOutput {
+            lint: vec![r#"(:checker "missing-language-in-src-block" :trust "high" :position 5 :message "Missing language in source block")"#.to_owned()],
+            ..emacs_output("(org-data (:begin 1 :contents-begin 1 :contents-end 21 :end 21))")
+        };
+        let owner_tree =
+            build_owner_tree(body, &emacs_output, DEFAULT_MAX_DEPTH).expect("Build owner tree");
+        let diagnostic = &owner_tree.lint[0];
+        assert_eq!(diagnostic.checker, "missing-language-in-src-block");
+        assert_eq!(diagnostic.message, "Missing language in source block");
+        assert_eq!(
+            (
+                diagnostic.position.start_line,
+                diagnostic.position.end_line,
+                diagnostic.position.start_character,
+                diagnostic.position.end_character
+            ),
+            (2, 3, 5, 17)
+        );
+    }
+
+    #[test]
+    fn lint_position_zero() {
+        let emacs_output = EmacsOutput {
+            lint: vec![r#"(:checker "foo" :trust "low" :position 0 :message "Bar")"#.to_owned()],
+            ..emacs_output("(org-data (:begin 1 :contents-begin 1 :contents-end 5 :end 5))")
+        };
+        let err = build_owner_tree("foo\n", &emacs_output, DEFAULT_MAX_DEPTH)
+            .err()
+            .expect("Position before the document");
+        match err {
+            OrgParseError::OwnerTree { path, message } => {
+                assert_eq!(path, ["lint[0]"]);
+                assert_eq!(message, "Positions start at 1.");
+            }
+            _ => panic!("Expected an owner tree error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn element_at_point_lineage() {
+        let body = "* Heading\n\nSome *bold* text\n";
+        let emacs_output = EmacsElementAtPoint {
+            at_point: "((paragraph (:begin 12 :post-affiliated 12 :contents-begin 12 :contents-end 29 :end 29 :post-blank 0)) (section (:begin 11 :post-affiliated 11 :contents-begin 11 :contents-end 29 :end 29 :post-blank 0)) (headline (:begin 1 :post-affiliated 1 :contents-begin 11 :contents-end 29 :end 29 :post-blank 0)) (org-data (:begin 1 :post-affiliated 1 :contents-begin 1 :contents-end 29 :end 29 :post-blank 0)))".to_owned(),
+            context: "((bold (:begin 17 :post-affiliated nil :contents-begin 18 :contents-end 22 :end 23 :post-blank 1)) (paragraph (:begin 12 :post-affiliated 12 :contents-begin 12 :contents-end 29 :end 29 :post-blank 0)))".to_owned(),
+            warnings: Vec::new(),
+        };
+        let element_at_point = build_element_at_point(body, 18, &emacs_output, DEFAULT_MAX_DEPTH)
+            .expect("Build lineage");
+        let names: Vec<&str> = element_at_point
+            .at_point
+            .iter()
+            .map(|node| node.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["org-data", "headline", "section", "paragraph"]);
+        let bold = element_at_point
+            .context
+            .last()
+            .expect("Context is not empty");
+        assert_eq!(bold.name, "bold");
+        assert_eq!(
+            (bold.position.start_line, bold.position.start_character),
+            (3, 17)
+        );
+    }
+
+    #[test]
+    fn deeply_nested() {
+        let depth = 30_000;
+        let ast = format!(
+            "(org-data (:begin 1 :end 5) {}{})",
+            "(bold (:begin 1 :end 5) ".repeat(depth),
+            ")".repeat(depth)
+        );
+        let owner_tree =
+            build_owner_tree("foo\n", &emacs_output(&ast), depth + 2).expect("Build owner tree");
+        let mut levels = 0;
+        let mut node = &owner_tree.tree;
+        while let Some(child) = node.children.first() {
+            assert_eq!(child.name, "bold");
+            node = child;
+            levels += 1;
+        }
+        assert_eq!(levels, depth);
+
+        let err = build_owner_tree("foo\n", &emacs_output(&ast), depth + 1)
+            .err()
+            .expect("Too deep");
+        assert!(matches!(err, OrgParseError::Sexp { .. }));
+    }
+
+    #[test]
+    fn cyclic_tree() {
+        // Emacs never prints a child as a reference to its ancestor, but a malformed AST must not send us around the cycle forever.
+        let ast = "#1=(org-data (:begin 1 :end 5) (bold (:begin 1 :end 5) #1#))";
+        let err = build_owner_tree("foo\n", &emacs_output(ast), 10)
+            .err()
+            .expect("Cycle");
+        match err {
+            OrgParseError::OwnerTree { path, message } => {
+                assert_eq!(message, "Nesting exceeds the maximum depth of 10.");
+                assert_eq!(path.len(), 11);
+                assert_eq!(path[..3], ["org-data", "bold[0]", "org-data[0]"]);
+            }
+            _ => panic!("Expected an owner tree error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn maximum_depth_serializes() {
+        // Serializing the response still recurses, which the largest maximum depth has to leave room for. Nodes nest both as children and inside property values.
+        let depth = MAX_SAFE_DEPTH - 2;
+        let nested_nodes = format!(
+            "(org-data (:begin 1 :end 5) {}{})",
+            "(bold (:begin 1 :end 5) ".repeat(depth),
+            ")".repeat(depth)
+        );
+        let nested_property = format!(
+            "(org-data (:begin 1 :end 5) (paragraph (:begin 1 :end 5 :value {}{})))",
+            "(".repeat(depth - 1),
+            ")".repeat(depth - 1)
+        );
+        for ast in [nested_nodes, nested_property] {
+            let output = emacs_output(&ast);
+            let owner_tree =
+                build_owner_tree("foo\n", &output, MAX_SAFE_DEPTH).expect("Build owner tree");
+            serde_json::to_string(&owner_tree).expect("Serialize");
+            let json_sexp_tree =
+                crate::sexp_json::build_json_sexp_tree("foo\n", &output, MAX_SAFE_DEPTH)
+                    .expect("Convert to JSON");
+            serde_json::to_string(&json_sexp_tree).expect("Serialize");
+        }
+    }
+}
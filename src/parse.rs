@@ -1,36 +1,405 @@
+use std::collections::BTreeMap;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde::Serialize;
 use tokio::process::Command;
 
+use crate::error::OrgParseError;
+use crate::org_settings::OrgSettings;
+use crate::worker_pool::new_sentinel;
+use crate::worker_pool::EmacsWorkerPool;
+use crate::worker_pool::Frame;
+
+/// Elisp run by every process in the `EmacsWorkerPool`.
+///
+/// Requests arrive on stdin, one per line, as a printed plist such as `(:sentinel "<sentinel>" :op parse :document "<base64>" :bindings ((tab-width . 4)) :report (tab-width) :granularity object :visible-only nil)`. The document is base64 encoded UTF-8 so arbitrary bytes (newlines, NUL, invalid escapes) survive the trip exactly and requests stay on a single line.
+///
+/// `:bindings` is an alist of the `OrgSettings` to bind while the buffer is set up and parsed. Variables that are always buffer-local (like `tab-width`, which `org-mode` also resets) are set again inside the buffer after `org-mode` runs. The value of every variable in `:report` is sent back in a `setting` frame as `<variable> <printed value>`. `org-inlinetask` is loaded up front in every worker so whether inline tasks are recognized never depends on which requests a worker has seen before.
+///
+/// After the AST, every `org-lint` checker is run against a fresh full parse of the same buffer and each report is sent as a `lint` frame holding `(:checker <name> :trust <high or low> :position <position> :message <message>)`. If linting itself fails a single `lint-error` frame is sent instead, so a broken checker never costs us the AST.
+///
+/// Each backend in `:export` is then exported with `org-export-as` and sent as an `export` frame holding `<backend> <exported text>`, or an `export-error` frame holding `<backend> <error message>` if that backend signaled. Export failures are kept per backend and separate from the `signal` frames so one failing exporter neither hides the AST nor the other exports. Every exporter is loaded up front for the same reason as `org-inlinetask`.
+///
+/// `:op at-point` sets up the buffer the same way, moves to `:point` and replies with `at-point` and `context` frames holding the printed lineage of `org-element-at-point` and `org-element-context`, innermost first. Each element is printed as `(<type> (:begin .. :post-affiliated .. :contents-begin .. :contents-end .. :end .. :post-blank ..))` rather than as the element itself, since the `:parent` chain would otherwise repeat every ancestor in full.
+///
+/// Replies are written to stdout as lines of `<sentinel> <kind> [<base64 payload>]`, terminated by `<sentinel> done`. `send-string-to-terminal` is used instead of `princ` because it flushes stdout. Errors signaled while handling a request are reported as a `signal` frame holding the error symbol, a `signal-message` frame and one `signal-data` frame per printed datum.
+///
+/// The handling of each request is bracketed by `<sentinel> begin` and `<sentinel> end` lines on stderr so anything else emacs prints (warnings, "Loading..." lines) can be attributed to the request that caused it.
+pub const WORKER_SCRIPT: &str = r#"(progn
+     (require 'cl-lib)
+     (require 'org)
+     (require 'org-inlinetask)
+     (require 'org-lint)
+     (require 'ox-ascii)
+     (require 'ox-html)
+     (require 'ox-latex)
+     (require 'ox-md)
+     (require 'ox-org)
+     (defvar org-investigation-sentinel nil)
+     (defun org-investigation-reply (kind &optional payload)
+       (send-string-to-terminal
+        (concat org-investigation-sentinel " " kind
+                (if payload
+                    (concat " " (base64-encode-string (encode-coding-string payload 'utf-8-unix t) t))
+                  "")
+                "\n")))
+     (defun org-investigation-with-document (request body)
+       (let ((bindings (plist-get request :bindings)))
+         (cl-progv (mapcar #'car bindings) (mapcar #'cdr bindings)
+           (with-temp-buffer
+             (insert (decode-coding-string
+                      (base64-decode-string (plist-get request :document))
+                      'utf-8-unix))
+             (org-mode)
+             (dolist (binding bindings)
+               (when (local-variable-if-set-p (car binding))
+                 (set (make-local-variable (car binding)) (cdr binding))))
+             (dolist (variable (plist-get request :report))
+               (org-investigation-reply
+                "setting"
+                (format "%s %S" variable
+                        (if (boundp variable) (symbol-value variable) 'unbound))))
+             (funcall body)))))
+     (defun org-investigation-lint ()
+       (condition-case err
+           (let ((ast (org-element-parse-buffer)))
+             (dolist (checker org-lint--checkers)
+               (dolist (report (save-excursion (funcall (org-lint-checker-function checker) ast)))
+                 (org-investigation-reply
+                  "lint"
+                  (prin1-to-string
+                   (list :checker (symbol-name (org-lint-checker-name checker))
+                         :trust (symbol-name (org-lint-checker-trust checker))
+                         :position (let ((position (car report)))
+                                     (if (markerp position) (marker-position position) position))
+                         :message (nth 1 report)))))))
+         (error (org-investigation-reply "lint-error" (error-message-string err)))))
+     (defun org-investigation-export (backends)
+       (dolist (backend backends)
+         (condition-case err
+             (org-investigation-reply
+              "export"
+              (concat (symbol-name backend) " " (org-export-as backend)))
+           (error
+            (org-investigation-reply
+             "export-error"
+             (concat (symbol-name backend) " " (error-message-string err)))))))
+     (defun org-investigation-lineage (element)
+       (prin1-to-string
+        (mapcar (lambda (ancestor)
+                  (list (org-element-type ancestor)
+                        (cl-loop for property in '(:begin :post-affiliated :contents-begin :contents-end :end :post-blank)
+                                 append (list property (org-element-property property ancestor)))))
+                (org-element-lineage element nil t))))
+     (while t
+       (let* ((request (read (read-from-minibuffer "")))
+              (org-investigation-sentinel (plist-get request :sentinel)))
+         (message "%s begin" org-investigation-sentinel)
+         (condition-case err
+             (pcase (plist-get request :op)
+               ('ping (org-investigation-reply "pong"))
+               ('parse
+                (org-investigation-with-document
+                 request
+                 (lambda ()
+                   (org-investigation-reply
+                    "ast"
+                    (pp-to-string
+                     (org-element-parse-buffer
+                      (plist-get request :granularity)
+                      (plist-get request :visible-only))))
+                   (org-investigation-lint)
+                   (org-investigation-export (plist-get request :export)))))
+               ('at-point
+                (org-investigation-with-document
+                 request
+                 (lambda ()
+                   (goto-char (plist-get request :point))
+                   (org-investigation-reply "at-point" (org-investigation-lineage (org-element-at-point)))
+                   (org-investigation-reply "context" (org-investigation-lineage (org-element-context))))))
+               (op (error "Unknown worker operation %S" op)))
+           (error
+            (org-investigation-reply "signal" (symbol-name (car err)))
+            (org-investigation-reply "signal-message" (error-message-string err))
+            (dolist (datum (if (listp (cdr err)) (cdr err) (list (cdr err))))
+              (org-investigation-reply "signal-data" (prin1-to-string datum)))))
+         (message "%s end" org-investigation-sentinel)
+         (org-investigation-reply "done")))
+)"#;
+
+/// Everything about how a document is parsed other than the document itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ParseOptions {
+    #[serde(default)]
+    pub settings: OrgSettings,
+    #[serde(default)]
+    pub granularity: Granularity,
+    /// Skip the contents of folded (invisible) parts of the document.
+    #[serde(default)]
+    pub visible_only: bool,
+    /// Export backends to preview the document with. Skipped when empty so options recorded before exports existed keep the same fixture names and cache keys.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub export_backends: Vec<ExportBackend>,
+}
+
+/// How deep `org-element-parse-buffer` descends into the document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Granularity {
+    /// Only parse headlines.
+    Headline,
+    /// Parse elements, but do not descend into greater elements other than headlines and sections. A paragraph in a section is parsed, while one inside a plain list or a drawer is not.
+    GreaterElement,
+    /// Parse every element but none of the objects inside them.
+    Element,
+    /// Parse everything, down to the objects and plain text.
+    #[default]
+    Object,
+}
+
+impl Granularity {
+    fn as_elisp(&self) -> &'static str {
+        match self {
+            Granularity::Headline => "headline",
+            Granularity::GreaterElement => "greater-element",
+            Granularity::Element => "element",
+            Granularity::Object => "object",
+        }
+    }
+}
+
+/// An `org-export` backend that can be previewed next to the AST.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportBackend {
+    Ascii,
+    Html,
+    Latex,
+    Md,
+    Org,
+}
+
+impl ExportBackend {
+    fn as_elisp(&self) -> &'static str {
+        match self {
+            ExportBackend::Ascii => "ascii",
+            ExportBackend::Html => "html",
+            ExportBackend::Latex => "latex",
+            ExportBackend::Md => "md",
+            ExportBackend::Org => "org",
+        }
+    }
+
+    fn from_elisp(name: &str) -> Option<ExportBackend> {
+        [
+            ExportBackend::Ascii,
+            ExportBackend::Html,
+            ExportBackend::Latex,
+            ExportBackend::Md,
+            ExportBackend::Org,
+        ]
+        .into_iter()
+        .find(|backend| backend.as_elisp() == name)
+    }
+}
+
+/// The result of exporting the document with one backend. Exactly one of `output` and `error` is set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Export {
+    pub backend: ExportBackend,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// The message of the error signaled while exporting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What emacs told us about a document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmacsOutput {
+    /// The output of `org-element-parse-buffer` as printed by `pp-to-string`.
+    pub ast: String,
+    /// Everything emacs printed while parsing that was not part of the AST.
+    pub warnings: Vec<String>,
+    /// The printed value of every `OrgSettings::VARIABLES` entry while the document was parsed.
+    pub effective_settings: BTreeMap<String, String>,
+    /// One printed plist per `org-lint` report.
+    #[serde(default)]
+    pub lint: Vec<String>,
+    /// One entry per requested `ParseOptions::export_backends`, in the same order.
+    #[serde(default)]
+    pub exports: Vec<Export>,
+}
+
 pub async fn emacs_parse_org_document<C>(
+    pool: &EmacsWorkerPool,
     file_contents: C,
-) -> Result<String, Box<dyn std::error::Error>>
+    options: &ParseOptions,
+) -> Result<EmacsOutput, OrgParseError>
 where
     C: AsRef<str>,
 {
-    let escaped_file_contents = escape_elisp_string(file_contents);
-    let elisp_script = format!(
-        r#"(progn
-     (erase-buffer)
-     (insert "{escaped_file_contents}")
-     (org-mode)
-     (message "%s" (pp-to-string (org-element-parse-buffer)))
-)"#,
-        escaped_file_contents = escaped_file_contents
+    let request = format!(
+        r#":op parse {document} :granularity {granularity} :visible-only {visible_only} :export ({export})"#,
+        document = document_request(file_contents.as_ref(), &options.settings)?,
+        granularity = options.granularity.as_elisp(),
+        visible_only = if options.visible_only { "t" } else { "nil" },
+        export = options
+            .export_backends
+            .iter()
+            .map(ExportBackend::as_elisp)
+            .collect::<Vec<_>>()
+            .join(" ")
     );
-    let mut cmd = Command::new("emacs");
-    let proc = cmd
-        .arg("-q")
-        .arg("--no-site-file")
-        .arg("--no-splash")
-        .arg("--batch")
-        .arg("--eval")
-        .arg(elisp_script);
+    let reply = pool.request(&request).await?;
+    if let Some(signal) = read_signal(&reply.frames)? {
+        return Err(signal);
+    }
+    let mut ast = None;
+    let mut effective_settings = BTreeMap::new();
+    let mut lint = Vec::new();
+    let mut exports = Vec::new();
+    let mut warnings = reply.warnings;
+    for frame in reply.frames {
+        match frame.kind.as_str() {
+            "ast" => ast = Some(String::from_utf8(frame.payload)?),
+            "setting" => {
+                let setting = String::from_utf8(frame.payload)?;
+                let (variable, value) = setting.split_once(' ').ok_or(OrgParseError::Io {
+                    message: format!("Malformed setting frame {:?}.", setting),
+                })?;
+                effective_settings.insert(variable.to_owned(), value.to_owned());
+            }
+            "lint" => lint.push(String::from_utf8(frame.payload)?),
+            "lint-error" => warnings.push(format!(
+                "org-lint failed: {}",
+                String::from_utf8_lossy(&frame.payload)
+            )),
+            "export" | "export-error" => {
+                let payload = String::from_utf8(frame.payload)?;
+                let (backend, text) = payload.split_once(' ').unwrap_or((&payload, ""));
+                let backend = ExportBackend::from_elisp(backend).ok_or(OrgParseError::Io {
+                    message: format!("Unknown export backend {:?}.", backend),
+                })?;
+                let text = Some(text.to_owned());
+                exports.push(if frame.kind == "export" {
+                    Export {
+                        backend,
+                        output: text,
+                        error: None,
+                    }
+                } else {
+                    Export {
+                        backend,
+                        output: None,
+                        error: text,
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(EmacsOutput {
+        ast: ast.ok_or(OrgParseError::Io {
+            message: "Emacs worker replied without an AST.".to_owned(),
+        })?,
+        warnings,
+        effective_settings,
+        lint,
+        exports,
+    })
+}
+
+/// What org thinks is at a position in a document, as printed by the worker's `at-point` operation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmacsElementAtPoint {
+    /// The lineage of `org-element-at-point`, innermost first.
+    pub at_point: String,
+    /// The lineage of `org-element-context`, innermost first.
+    pub context: String,
+    pub warnings: Vec<String>,
+}
+
+/// Ask org what element and object are at `offset`, a zero-based character (not byte) offset into the document.
+pub async fn emacs_element_at_point<C>(
+    pool: &EmacsWorkerPool,
+    file_contents: C,
+    settings: &OrgSettings,
+    offset: usize,
+) -> Result<EmacsElementAtPoint, OrgParseError>
+where
+    C: AsRef<str>,
+{
+    let length = file_contents.as_ref().chars().count();
+    if offset > length {
+        return Err(OrgParseError::InvalidOffset { offset, length });
+    }
+    let request = format!(
+        r#":op at-point {document} :point {point}"#,
+        document = document_request(file_contents.as_ref(), settings)?,
+        point = offset + 1
+    );
+    let reply = pool.request(&request).await?;
+    if let Some(signal) = read_signal(&reply.frames)? {
+        return Err(signal);
+    }
+    let mut at_point = None;
+    let mut context = None;
+    for frame in reply.frames {
+        match frame.kind.as_str() {
+            "at-point" => at_point = Some(String::from_utf8(frame.payload)?),
+            "context" => context = Some(String::from_utf8(frame.payload)?),
+            _ => {}
+        }
+    }
+    Ok(EmacsElementAtPoint {
+        at_point: at_point.ok_or(OrgParseError::Io {
+            message: "Emacs worker replied without the element at point.".to_owned(),
+        })?,
+        context: context.ok_or(OrgParseError::Io {
+            message: "Emacs worker replied without the context at point.".to_owned(),
+        })?,
+        warnings: reply.warnings,
+    })
+}
+
+/// The part of a request shared by every operation that works on a document: the document itself and the settings to parse it with.
+fn document_request(file_contents: &str, settings: &OrgSettings) -> Result<String, OrgParseError> {
+    settings
+        .validate()
+        .map_err(|message| OrgParseError::InvalidSettings { message })?;
+    Ok(format!(
+        r#":document "{encoded_file_contents}" :bindings {bindings} :report ({report})"#,
+        encoded_file_contents = STANDARD.encode(file_contents),
+        bindings = settings.to_elisp_bindings(),
+        report = OrgSettings::VARIABLES.join(" ")
+    ))
+}
 
-    let out = proc.output().await?;
-    out.status.exit_ok()?;
-    Ok(String::from_utf8(out.stderr)?)
+/// Collect the `signal` frames of a reply into an `OrgParseError::Signal`, if there are any.
+fn read_signal(frames: &[Frame]) -> Result<Option<OrgParseError>, OrgParseError> {
+    let mut symbol = None;
+    let mut message = String::new();
+    let mut data = Vec::new();
+    for frame in frames {
+        match frame.kind.as_str() {
+            "signal" => symbol = Some(String::from_utf8(frame.payload.clone())?),
+            "signal-message" => message = String::from_utf8(frame.payload.clone())?,
+            "signal-data" => data.push(String::from_utf8(frame.payload.clone())?),
+            _ => {}
+        }
+    }
+    Ok(symbol.map(|symbol| OrgParseError::Signal {
+        symbol,
+        message,
+        data,
+    }))
 }
 
-fn escape_elisp_string<C>(file_contents: C) -> String
+/// Escape text so it can be placed between double quotes in an elisp string literal.
+pub(crate) fn escape_elisp_string<C>(file_contents: C) -> String
 where
     C: AsRef<str>,
 {
@@ -52,10 +421,14 @@ where
     output
 }
 
-pub async fn get_emacs_version() -> Result<String, Box<dyn std::error::Error>> {
-    let elisp_script = r#"(progn
-     (message "%s" (version))
-)"#;
+pub async fn get_emacs_version() -> Result<String, OrgParseError> {
+    let sentinel = new_sentinel();
+    let elisp_script = format!(
+        r#"(progn
+     (message "%s %s" "{sentinel}" (version))
+)"#,
+        sentinel = sentinel
+    );
     let mut cmd = Command::new("emacs");
     let proc = cmd
         .arg("-q")
@@ -65,16 +438,18 @@ pub async fn get_emacs_version() -> Result<String, Box<dyn std::error::Error>> {
         .arg("--eval")
         .arg(elisp_script);
 
-    let out = proc.output().await?;
-    out.status.exit_ok()?;
-    Ok(String::from_utf8(out.stderr)?)
+    run_one_shot(proc, &sentinel).await
 }
 
-pub async fn get_org_mode_version() -> Result<String, Box<dyn std::error::Error>> {
-    let elisp_script = r#"(progn
+pub async fn get_org_mode_version() -> Result<String, OrgParseError> {
+    let sentinel = new_sentinel();
+    let elisp_script = format!(
+        r#"(progn
      (org-mode)
-     (message "%s" (org-version nil t nil))
-)"#;
+     (message "%s %s" "{sentinel}" (org-version nil t nil))
+)"#,
+        sentinel = sentinel
+    );
     let mut cmd = Command::new("emacs");
     let proc = cmd
         .arg("-q")
@@ -84,7 +459,300 @@ pub async fn get_org_mode_version() -> Result<String, Box<dyn std::error::Error>
         .arg("--eval")
         .arg(elisp_script);
 
-    let out = proc.output().await?;
-    out.status.exit_ok()?;
-    Ok(String::from_utf8(out.stderr)?)
+    run_one_shot(proc, &sentinel).await
+}
+
+/// Run a single emacs invocation to completion and return the rest of the stderr line that starts with `sentinel`.
+async fn run_one_shot(proc: &mut Command, sentinel: &str) -> Result<String, OrgParseError> {
+    let out = proc.output().await.map_err(|e| OrgParseError::Spawn {
+        message: e.to_string(),
+    })?;
+    out.status
+        .exit_ok()
+        .map_err(|e| OrgParseError::NonZeroExit { status: e.code() })?;
+    let stderr = String::from_utf8(out.stderr)?;
+    let prefix = format!("{} ", sentinel);
+    stderr
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .map(str::to_owned)
+        .ok_or(OrgParseError::Io {
+            message: "Emacs did not print the expected output.".to_owned(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::org_settings::TodoKeywordSequence;
+    use crate::org_settings::TodoKeywordSequenceType;
+    use crate::sexp::sexp_with_padding;
+    use crate::worker_pool::WorkerPoolConfig;
+
+    // These tests need emacs on the PATH, so they are ignored by default. Run them with `cargo test -- --ignored`.
+
+    /// The original transport: the document is escaped into an elisp string literal and pasted into `--eval`.
+    async fn emacs_parse_org_document_escaped(
+        file_contents: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let escaped_file_contents = escape_elisp_string(file_contents);
+        let elisp_script = format!(
+            r#"(progn
+     (erase-buffer)
+     (insert "{escaped_file_contents}")
+     (org-mode)
+     (message "%s" (pp-to-string (org-element-parse-buffer)))
+)"#,
+            escaped_file_contents = escaped_file_contents
+        );
+        let mut cmd = Command::new("emacs");
+        let proc = cmd
+            .arg("-q")
+            .arg("--no-site-file")
+            .arg("--no-splash")
+            .arg("--batch")
+            .arg("--eval")
+            .arg(elisp_script);
+
+        let out = proc.output().await?;
+        out.status.exit_ok()?;
+        Ok(String::from_utf8(out.stderr)?)
+    }
+
+    /// Read the end position of the org-data node, which covers the whole document.
+    fn document_end(ast: &str) -> usize {
+        let (_remaining, parsed) = sexp_with_padding(ast).expect("Parse the AST");
+        let attributes = parsed.as_list().expect("org-data is a list")[1]
+            .as_plist("org-data")
+            .expect("Attributes are a plist");
+        match attributes.get(":standard-properties") {
+            Some(standard_properties) => standard_properties.as_vector().expect("Vector")[4]
+                .as_atom()
+                .expect("End is an atom")
+                .parse()
+                .expect("End is a number"),
+            None => attributes
+                .get_usize(":end")
+                .expect("End is a number")
+                .expect("org-data has an end"),
+        }
+    }
+
+    /// The two transports parse in different buffers, so blank out the buffer names before comparing.
+    fn normalize(ast: &str) -> String {
+        let mut output = String::with_capacity(ast.len());
+        let mut remaining = ast.trim();
+        while let Some(start) = remaining.find("#<buffer ") {
+            output.push_str(&remaining[..start]);
+            output.push_str("#<buffer>");
+            let end = remaining[start..].find('>').expect("Buffer is closed") + start;
+            remaining = &remaining[(end + 1)..];
+        }
+        output.push_str(remaining);
+        output
+    }
+
+    fn test_pool() -> EmacsWorkerPool {
+        EmacsWorkerPool::new(WorkerPoolConfig {
+            size: 1,
+            request_timeout: Duration::from_secs(60),
+        })
+    }
+
+    async fn parse_both_ways(input: &str) -> (String, String) {
+        let pool = test_pool();
+        let via_stdin = emacs_parse_org_document(&pool, input, &ParseOptions::default())
+            .await
+            .expect("Parse through the worker")
+            .ast;
+        let via_eval = emacs_parse_org_document_escaped(input)
+            .await
+            .expect("Parse through --eval");
+        (normalize(&via_stdin), normalize(&via_eval))
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unicode_matches_escaped_path() {
+        let input = "* 見出し :tag:\n\nπ ≈ 3.14 😀 \"quoted\" back\\slash\n\n- item \u{1F600}\n";
+        let (via_stdin, via_eval) = parse_both_ways(input).await;
+        assert_eq!(via_stdin, via_eval);
+        assert_eq!(document_end(&via_stdin), input.chars().count() + 1);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn control_characters_match_escaped_path() {
+        let input = "foo\tbar\x0cbaz\x1b[0m\r\n\r\nlorem\r\n";
+        let (via_stdin, via_eval) = parse_both_ways(input).await;
+        assert_eq!(via_stdin, via_eval);
+        assert_eq!(document_end(&via_stdin), input.chars().count() + 1);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn nul_is_preserved() {
+        // NUL cannot be passed in an argument at all, so there is no escaped path to compare against.
+        let input = "foo\0bar\n\nbaz\0\n";
+        let pool = test_pool();
+        let ast = emacs_parse_org_document(&pool, input, &ParseOptions::default())
+            .await
+            .expect("Parse through the worker")
+            .ast;
+        assert_eq!(document_end(&ast), input.chars().count() + 1);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn large_input() {
+        // Well past the 128KiB limit on a single argument.
+        let input = "* Heading\n\nSome paragraph text with ünïcödé.\n\n".repeat(20_000);
+        let pool = test_pool();
+        let ast = emacs_parse_org_document(&pool, &input, &ParseOptions::default())
+            .await
+            .expect("Parse through the worker")
+            .ast;
+        assert_eq!(document_end(&ast), input.chars().count() + 1);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn lint() {
+        let input = "#+BEGIN_SRC\nfoo\n#+END_SRC\n";
+        let pool = test_pool();
+        let output = emacs_parse_org_document(&pool, input, &ParseOptions::default())
+            .await
+            .expect("Parse through the worker");
+        assert!(output
+            .lint
+            .iter()
+            .any(|report| report.contains("missing-language-in-src-block")));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn export() {
+        let input = "* Heading\n\nSome *bold* text\n";
+        let pool = test_pool();
+        let options = ParseOptions {
+            export_backends: vec![ExportBackend::Html, ExportBackend::Md],
+            ..Default::default()
+        };
+        let output = emacs_parse_org_document(&pool, input, &options)
+            .await
+            .expect("Parse through the worker");
+        assert_eq!(output.exports.len(), 2);
+        let html = output.exports[0].output.as_ref().expect("HTML export");
+        assert!(html.contains("<b>bold</b>"));
+        let md = output.exports[1].output.as_ref().expect("Markdown export");
+        assert!(md.contains("**bold**"));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn element_at_point() {
+        let input = "* Heading\n\nSome *bold* text\n";
+        let pool = test_pool();
+        // Offset 18 is the "o" in "bold".
+        let output = emacs_element_at_point(&pool, input, &OrgSettings::default(), 18)
+            .await
+            .expect("Look up the element at point");
+        assert!(output.at_point.starts_with("((paragraph "));
+        assert!(output.context.starts_with("((bold "));
+        let err = emacs_element_at_point(&pool, input, &OrgSettings::default(), 29)
+            .await
+            .expect_err("Offset past the end");
+        assert!(matches!(err, OrgParseError::InvalidOffset { .. }));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn timeout() {
+        let pool = EmacsWorkerPool::new(WorkerPoolConfig {
+            size: 1,
+            request_timeout: Duration::from_millis(1),
+        });
+        let err = emacs_parse_org_document(
+            &pool,
+            "* Heading\n".repeat(10_000),
+            &ParseOptions::default(),
+        )
+        .await
+        .expect_err("Parse should time out");
+        assert!(matches!(err, OrgParseError::Timeout { .. }));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn settings_are_bound() {
+        let pool = test_pool();
+        let settings = OrgSettings {
+            org_todo_keywords: Some(vec![TodoKeywordSequence {
+                sequence_type: TodoKeywordSequenceType::Sequence,
+                keywords: vec!["NEXT".to_owned(), "|".to_owned(), "FINISHED".to_owned()],
+            }]),
+            tab_width: Some(4),
+            ..Default::default()
+        };
+        let options = ParseOptions {
+            settings,
+            ..Default::default()
+        };
+        let output = emacs_parse_org_document(&pool, "* NEXT Heading\n", &options)
+            .await
+            .expect("Parse through the worker");
+        assert!(output.ast.contains(r#":todo-keyword #("NEXT""#));
+        assert_eq!(
+            output
+                .effective_settings
+                .get("tab-width")
+                .map(String::as_str),
+            Some("4")
+        );
+
+        // Settings must not leak into the next request handled by the same worker.
+        let output = emacs_parse_org_document(&pool, "* NEXT Heading\n", &ParseOptions::default())
+            .await
+            .expect("Parse through the worker");
+        assert!(!output.ast.contains(":todo-keyword #("));
+        assert_eq!(
+            output
+                .effective_settings
+                .get("tab-width")
+                .map(String::as_str),
+            Some("8")
+        );
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn granularity() {
+        let pool = test_pool();
+        let input = "* Heading\n\nSome *bold* text.\n";
+        for granularity in [
+            Granularity::Headline,
+            Granularity::GreaterElement,
+            Granularity::Element,
+            Granularity::Object,
+        ] {
+            let options = ParseOptions {
+                granularity,
+                ..Default::default()
+            };
+            let output = emacs_parse_org_document(&pool, input, &options)
+                .await
+                .expect("Parse through the worker");
+            // Even at greater-element granularity org parses into sections, where the paragraph is.
+            assert_eq!(
+                output.ast.contains("(paragraph"),
+                granularity != Granularity::Headline
+            );
+            assert_eq!(
+                output.ast.contains("(bold"),
+                granularity == Granularity::Object
+            );
+        }
+    }
 }
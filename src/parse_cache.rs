@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::stable_hash;
+use crate::parse::EmacsOutput;
+use crate::parse::ParseOptions;
+
+/// Everything that determines what emacs will produce for a parse.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub document: String,
+    pub options: ParseOptions,
+    pub emacs_version: String,
+    pub org_mode_version: String,
+}
+
+impl CacheKey {
+    /// The content address of this key, which is also the name of the file it is persisted under.
+    fn hash(&self) -> u64 {
+        let serialized = serde_json::to_string(self).expect("Serializing a cache key cannot fail.");
+        stable_hash(serialized.as_bytes())
+    }
+}
+
+/// A cached parse as persisted on disk. The full key is kept so hash collisions are detected instead of serving the wrong AST.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    output: EmacsOutput,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A least-recently-used cache of emacs output, optionally persisted to a directory so it survives restarts.
+pub struct ParseCache {
+    capacity: usize,
+    directory: Option<PathBuf>,
+    state: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct LruState {
+    entries: HashMap<u64, (u64, CacheEntry)>,
+    /// Maps the tick an entry was last used at to its hash, so the oldest entry is always first.
+    recency: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl LruState {
+    fn touch(&mut self, hash: u64) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((last_used, _)) = self.entries.get_mut(&hash) {
+            self.recency.remove(last_used);
+            *last_used = tick;
+            self.recency.insert(tick, hash);
+        }
+    }
+
+    /// Insert an entry and return the hashes of any entries evicted to make room for it.
+    fn insert(&mut self, hash: u64, entry: CacheEntry, capacity: usize) -> Vec<u64> {
+        self.tick += 1;
+        if let Some((last_used, _)) = self.entries.insert(hash, (self.tick, entry)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, hash);
+        let mut evicted = Vec::new();
+        while self.entries.len() > capacity {
+            let (_, oldest) = self
+                .recency
+                .pop_first()
+                .expect("Recency has one record per entry.");
+            self.entries.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+}
+
+impl ParseCache {
+    /// Create an empty cache holding at most `capacity` parses. A capacity of zero disables caching.
+    pub fn new(capacity: usize, directory: Option<PathBuf>) -> ParseCache {
+        ParseCache {
+            capacity,
+            directory,
+            state: Mutex::new(LruState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Configure the cache from `ORG_INVESTIGATION_CACHE_SIZE` (default 256 entries) and `ORG_INVESTIGATION_CACHE_DIR` (no persistence if unset), loading anything previously persisted.
+    pub async fn from_env() -> Result<ParseCache, Box<dyn std::error::Error>> {
+        let capacity = match std::env::var("ORG_INVESTIGATION_CACHE_SIZE") {
+            Ok(val) => val.parse::<usize>()?,
+            Err(_) => 256,
+        };
+        let directory = std::env::var_os("ORG_INVESTIGATION_CACHE_DIR").map(PathBuf::from);
+        let cache = ParseCache::new(capacity, directory);
+        cache.load().await?;
+        Ok(cache)
+    }
+
+    /// Read every persisted entry from the cache directory, oldest first so the most recently written ones survive if there are more than fit.
+    async fn load(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let directory = match &self.directory {
+            Some(directory) if self.capacity > 0 => directory,
+            _ => return Ok(()),
+        };
+        tokio::fs::create_dir_all(directory).await?;
+        let mut files = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(directory).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                files.push((entry.metadata().await?.modified()?, path));
+            }
+        }
+        files.sort();
+        for (_, path) in files {
+            let contents = tokio::fs::read_to_string(&path).await?;
+            let entry: CacheEntry = match serde_json::from_str(&contents) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // A half-written or outdated entry only costs us a re-parse.
+                    eprintln!("Ignoring unreadable cache entry {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let hash = entry.key.hash();
+            let evicted = self.state.lock().expect("Cache mutex poisoned.").insert(
+                hash,
+                entry,
+                self.capacity,
+            );
+            self.remove_files(evicted).await;
+        }
+        Ok(())
+    }
+
+    /// Look up a previous parse, counting the lookup as a hit or a miss.
+    pub fn get(&self, key: &CacheKey) -> Option<EmacsOutput> {
+        let hash = key.hash();
+        let mut state = self.state.lock().expect("Cache mutex poisoned.");
+        let output = match state.entries.get(&hash) {
+            Some((_, entry)) if entry.key == *key => Some(entry.output.clone()),
+            _ => None,
+        };
+        if output.is_some() {
+            state.touch(hash);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        output
+    }
+
+    /// Remember a parse, persisting it if the cache has a directory. Failing to persist is reported but does not fail the request.
+    pub async fn insert(&self, key: CacheKey, output: EmacsOutput) {
+        if self.capacity == 0 {
+            return;
+        }
+        let hash = key.hash();
+        let entry = CacheEntry { key, output };
+        let serialized = self.directory.as_ref().map(|_| {
+            serde_json::to_string(&entry).expect("Serializing a cache entry cannot fail.")
+        });
+        let evicted =
+            self.state
+                .lock()
+                .expect("Cache mutex poisoned.")
+                .insert(hash, entry, self.capacity);
+        if let (Some(directory), Some(serialized)) = (&self.directory, serialized) {
+            let path = directory.join(format!("{:016x}.json", hash));
+            if let Err(e) = tokio::fs::write(&path, serialized).await {
+                eprintln!("Failed to persist cache entry {}: {}", path.display(), e);
+            }
+        }
+        self.remove_files(evicted).await;
+    }
+
+    async fn remove_files(&self, hashes: Vec<u64>) {
+        if let Some(directory) = &self.directory {
+            for hash in hashes {
+                // The file may never have been written, so there is nothing useful to do with an error here.
+                let _ = tokio::fs::remove_file(directory.join(format!("{:016x}.json", hash))).await;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity,
+            entries: self
+                .state
+                .lock()
+                .expect("Cache mutex poisoned.")
+                .entries
+                .len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn key(document: &str) -> CacheKey {
+        CacheKey {
+            document: document.to_owned(),
+            options: ParseOptions::default(),
+            emacs_version: "GNU Emacs 29.1".to_owned(),
+            org_mode_version: "9.6.6".to_owned(),
+        }
+    }
+
+    fn output(ast: &str) -> EmacsOutput {
+        EmacsOutput {
+            ast: ast.to_owned(),
+            warnings: Vec::new(),
+            effective_settings: BTreeMap::new(),
+            lint: Vec::new(),
+            exports: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_is_evicted() {
+        let cache = ParseCache::new(2, None);
+        cache.insert(key("a"), output("(a)")).await;
+        cache.insert(key("b"), output("(b)")).await;
+        assert!(cache.get(&key("a")).is_some());
+        cache.insert(key("c"), output("(c)")).await;
+        assert!(cache.get(&key("b")).is_none());
+        assert_eq!(cache.get(&key("a")).expect("Cached").ast, "(a)");
+        assert_eq!(cache.get(&key("c")).expect("Cached").ast, "(c)");
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                capacity: 2,
+                entries: 2,
+                hits: 3,
+                misses: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn versions_are_part_of_the_key() {
+        let cache = ParseCache::new(2, None);
+        cache.insert(key("a"), output("(a)")).await;
+        let newer_org = CacheKey {
+            org_mode_version: "9.7".to_owned(),
+            ..key("a")
+        };
+        assert!(cache.get(&newer_org).is_none());
+    }
+
+    #[tokio::test]
+    async fn persisted_across_restarts() {
+        let directory =
+            std::env::temp_dir().join(format!("org-investigation-cache-{}", std::process::id()));
+        let cache = ParseCache::new(1, Some(directory.clone()));
+        cache.load().await.expect("Load empty cache");
+        cache.insert(key("a"), output("(a)")).await;
+        cache.insert(key("b"), output("(b)")).await;
+
+        let restarted = ParseCache::new(1, Some(directory.clone()));
+        restarted.load().await.expect("Load cache");
+        assert!(restarted.get(&key("a")).is_none());
+        assert_eq!(restarted.get(&key("b")).expect("Persisted").ast, "(b)");
+        std::fs::remove_dir_all(&directory).expect("Clean up cache");
+    }
+}
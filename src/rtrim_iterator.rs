@@ -1,4 +1,5 @@
 /// Removes 1 character from the end of an iterator if it matches needle
+#[allow(dead_code)]
 pub struct RTrimIterator<I> {
     iter: I,
     needle: char,
@@ -44,6 +45,7 @@ where
 }
 
 impl<I> RTrimIterator<I> {
+    #[allow(dead_code)]
     pub fn new(iter: I, needle: char) -> RTrimIterator<I> {
         RTrimIterator {
             iter,
@@ -53,6 +55,7 @@ impl<I> RTrimIterator<I> {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
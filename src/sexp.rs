@@ -1,9 +1,15 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 
 use nom::branch::alt;
 use nom::bytes::complete::escaped;
 use nom::bytes::complete::tag;
 use nom::bytes::complete::take_till1;
+use nom::bytes::complete::take_while_m_n;
+use nom::character::complete::alphanumeric1;
+use nom::character::complete::anychar;
+use nom::character::complete::digit1;
+use nom::character::complete::hex_digit1;
 use nom::character::complete::multispace0;
 use nom::character::complete::multispace1;
 use nom::character::complete::one_of;
@@ -11,22 +17,78 @@ use nom::combinator::map;
 use nom::combinator::not;
 use nom::combinator::opt;
 use nom::combinator::peek;
-use nom::multi::separated_list1;
+use nom::combinator::recognize;
+use nom::multi::many1_count;
 use nom::sequence::delimited;
 use nom::sequence::preceded;
+use nom::sequence::terminated;
 use nom::sequence::tuple;
 
+use serde::Serialize;
+
+use crate::error::CustomError;
+use crate::error::MyError;
+use crate::error::OrgParseError;
 use crate::error::Res;
 
+/// A token read from a sexp, along with where it was read from.
 #[derive(Debug)]
-pub enum Token<'s> {
+pub struct Token<'s> {
+    pub kind: TokenKind<'s>,
+    pub span: Span,
+}
+
+/// A range of bytes in the input a token was read from. `end` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Tokens are equal when they read as the same value, wherever they were read from.
+impl<'s> PartialEq for Token<'s> {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TokenKind<'s> {
     Atom(&'s str),
     List(Vec<Token<'s>>),
     TextWithProperties(TextWithProperties<'s>),
     Vector(Vec<Token<'s>>),
+    /// `#N=` followed by the token it labels, as printed when `print-circle` is non-nil.
+    Labeled(usize, Box<Token<'s>>),
+    /// `#N#`, a reference back to the token labeled `#N=`. Follow it with `Labels::resolve`.
+    Reference(usize),
+    /// `(a b . c)`, a list whose final cdr is not nil.
+    DottedList(Vec<Token<'s>>, Box<Token<'s>>),
+    /// `#s(...)`, a record or (when the first element is `hash-table`) a hash table.
+    Record(Vec<Token<'s>>),
+    /// `#[...]`, a byte-code function object, as printed for compiled functions held in a property.
+    ByteCode(Vec<Token<'s>>),
+    /// `#&N"..."`, a bool-vector of length N whose bits are packed into the characters of the string.
+    BoolVector {
+        length: usize,
+        bits: &'s str,
+    },
+    /// A character literal, kept as its source such as `?a` or `?\C-x`.
+    Character(&'s str),
+    /// `'x`, `` `x ``, `,x`, `,@x` or `#'x`, which `pp` prints in place of `(quote x)` and friends.
+    ReaderMacro(ReaderMacro, Box<Token<'s>>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReaderMacro {
+    Quote,
+    Backquote,
+    Unquote,
+    Splice,
+    Function,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct TextWithProperties<'s> {
     #[allow(dead_code)]
     pub text: &'s str,
@@ -36,224 +98,1053 @@ pub struct TextWithProperties<'s> {
 
 impl<'s> TextWithProperties<'s> {
     pub fn unquote(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let mut out = String::with_capacity(self.text.len());
-        if !self.text.starts_with(r#"""#) {
-            return Err("Quoted text does not start with quote.".into());
-        }
-        if !self.text.ends_with(r#"""#) {
-            return Err("Quoted text does not end with quote.".into());
-        }
-        let interior_text = &self.text[1..(self.text.len() - 1)];
-        let mut state = ParseState::Normal;
-        for current_char in interior_text.chars().into_iter() {
-            state = match (state, current_char) {
-                (ParseState::Normal, '\\') => ParseState::Escape,
-                (ParseState::Normal, _) => {
-                    out.push(current_char);
-                    ParseState::Normal
-                }
-                (ParseState::Escape, 'n') => {
-                    out.push('\n');
-                    ParseState::Normal
-                }
-                (ParseState::Escape, '\\') => {
-                    out.push('\\');
-                    ParseState::Normal
+        unquote(self.text)
+    }
+}
+
+/// Turn the source of a quoted string, including its surrounding quotes, into the string it represents.
+///
+/// This follows the escapes the emacs reader accepts in strings. Characters with the meta modifier (and octal escapes from `\200` to `\377`) are raw bytes in emacs, which are represented here by the code point of the same value.
+pub fn unquote(text: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut out = String::with_capacity(text.len());
+    if !text.starts_with(r#"""#) {
+        return Err("Quoted text does not start with quote.".into());
+    }
+    if !text.ends_with(r#"""#) || text.len() < 2 {
+        return Err("Quoted text does not end with quote.".into());
+    }
+    let interior_text = &text[1..(text.len() - 1)];
+    let mut chars = interior_text.chars().peekable();
+    while let Some(current_char) = chars.next() {
+        if current_char != '\\' {
+            out.push(current_char);
+            continue;
+        }
+        match chars.peek() {
+            // An escaped newline continues the string on the next line, and an escaped space separates a hex escape from a following hex digit. Neither is part of the string.
+            Some('\n') | Some(' ') => {
+                chars.next();
+            }
+            _ => {
+                let code = read_escape(&mut chars)?;
+                out.push(string_character(code)?);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Set on a character code by `\M-`, as in emacs.
+const META_MODIFIER: u32 = 1 << 27;
+/// Set on a character code by `\C-` or `\^` when there is no ASCII control character for it, as in emacs.
+const CONTROL_MODIFIER: u32 = 1 << 26;
+/// The largest character code emacs supports, beyond Unicode for raw bytes.
+const MAX_CHARACTER: u32 = 0x3FFFFF;
+
+/// Read the escape sequence following a backslash, returning the character code along with any modifier bits.
+fn read_escape<I>(chars: &mut std::iter::Peekable<I>) -> Result<u32, Box<dyn std::error::Error>>
+where
+    I: Iterator<Item = char>,
+{
+    let escape = chars
+        .next()
+        .ok_or("Quoted text ends with a lone backslash.")?;
+    Ok(match escape {
+        'a' => 0x07,
+        'b' => 0x08,
+        'd' => 0x7F,
+        'e' => 0x1B,
+        'f' => 0x0C,
+        'n' => 0x0A,
+        'r' => 0x0D,
+        't' => 0x09,
+        'v' => 0x0B,
+        's' if chars.peek() != Some(&'-') => 0x20,
+        'M' | 'C' | 'S' | 'H' | 'A' | 's' if chars.peek() == Some(&'-') => {
+            chars.next();
+            let modified = read_modified(chars)?;
+            match escape {
+                'M' => modified | META_MODIFIER,
+                'C' => control(modified),
+                _ => return Err(format!("Invalid modifier \\{}- in string.", escape).into()),
+            }
+        }
+        '^' => control(read_modified(chars)?),
+        '0'..='7' => {
+            let mut code = escape.to_digit(8).expect("Matched an octal digit.");
+            for _ in 0..2 {
+                match chars.peek().and_then(|c| c.to_digit(8)) {
+                    Some(digit) => {
+                        code = code * 8 + digit;
+                        chars.next();
+                    }
+                    None => break,
                 }
-                (ParseState::Escape, '"') => {
-                    out.push('"');
-                    ParseState::Normal
+            }
+            code
+        }
+        'x' => {
+            let mut digits = String::new();
+            while let Some(c) = chars.next_if(char::is_ascii_hexdigit) {
+                digits.push(c);
+            }
+            if digits.is_empty() {
+                return Err("Hex escape \\x has no digits.".into());
+            }
+            let code = u32::from_str_radix(&digits, 16)
+                .map_err(|_| format!("Hex escape \\x{} is out of range.", digits))?;
+            if code > MAX_CHARACTER {
+                return Err(format!("Hex escape \\x{} is out of range.", digits).into());
+            }
+            code
+        }
+        'u' => read_exact_hex(chars, 'u', 4)?,
+        'U' => read_exact_hex(chars, 'U', 8)?,
+        'N' => {
+            if chars.next() != Some('{') {
+                return Err("Expected { after \\N.".into());
+            }
+            let mut name = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(format!("Unterminated \\N{{{}.", name).into()),
                 }
-                _ => todo!(),
-            };
+            }
+            // Emacs allows the name to be split across lines.
+            let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+            match name.strip_prefix("U+") {
+                Some(hex) => u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|code| char::from_u32(*code).is_some())
+                    .ok_or_else(|| format!("Invalid code point in \\N{{{}}}.", name))?,
+                None => unicode_names2::character(&name)
+                    .map(u32::from)
+                    .ok_or_else(|| format!("Unknown character name in \\N{{{}}}.", name))?,
+            }
         }
+        other => u32::from(other),
+    })
+}
+
+/// Read the character a `\C-`, `\^` or `\M-` modifier applies to, which may itself be escaped.
+fn read_modified<I>(chars: &mut std::iter::Peekable<I>) -> Result<u32, Box<dyn std::error::Error>>
+where
+    I: Iterator<Item = char>,
+{
+    match chars.next() {
+        Some('\\') => read_escape(chars),
+        Some(c) => Ok(u32::from(c)),
+        None => Err("Quoted text ends in the middle of a modifier.".into()),
+    }
+}
 
-        Ok(out)
+/// Apply the control modifier the way the emacs reader does, turning letters and `@` through `_` into ASCII control characters and `?` into DEL.
+fn control(code: u32) -> u32 {
+    let modifiers = code & (META_MODIFIER | CONTROL_MODIFIER);
+    let base = code & !(META_MODIFIER | CONTROL_MODIFIER);
+    if base == u32::from('?') {
+        0x7F | modifiers
+    } else if (u32::from('a')..=u32::from('z')).contains(&base) || (0x40..=0x5F).contains(&base) {
+        (base & 0x1F) | modifiers
+    } else {
+        code | CONTROL_MODIFIER
     }
 }
 
-enum ParseState {
-    Normal,
-    Escape,
+fn read_exact_hex<I>(
+    chars: &mut std::iter::Peekable<I>,
+    escape: char,
+    length: usize,
+) -> Result<u32, Box<dyn std::error::Error>>
+where
+    I: Iterator<Item = char>,
+{
+    let digits: String = (0..length)
+        .map_while(|_| chars.next_if(char::is_ascii_hexdigit))
+        .collect();
+    if digits.len() != length {
+        return Err(format!(
+            "\\{} must be followed by exactly {} hex digits, got {:?}.",
+            escape, length, digits
+        )
+        .into());
+    }
+    let code = u32::from_str_radix(&digits, 16)?;
+    if char::from_u32(code).is_none() {
+        return Err(format!("\\{}{} is not a valid code point.", escape, digits).into());
+    }
+    Ok(code)
+}
+
+/// Turn a character code from `read_escape` into the character it stands for in a string, where only the meta modifier is allowed.
+fn string_character(code: u32) -> Result<char, Box<dyn std::error::Error>> {
+    if code & CONTROL_MODIFIER != 0 {
+        return Err(format!(
+            "Invalid control modifier on {:?} in string.",
+            char::from_u32(code & !(META_MODIFIER | CONTROL_MODIFIER))
+        )
+        .into());
+    }
+    let code = if code & META_MODIFIER != 0 {
+        let base = code & !META_MODIFIER;
+        if base > 0x7F {
+            return Err("Invalid meta modifier on a non-ASCII character in string.".into());
+        }
+        base | 0x80
+    } else {
+        code
+    };
+    char::from_u32(code).ok_or_else(|| {
+        format!(
+            "Character code {:#x} is a raw byte or not a Unicode scalar value.",
+            code
+        )
+        .into()
+    })
+}
+
+/// The value of an integer atom, written in decimal or with any of the `#x`, `#o`, `#b` or `#NrDIGITS` radix prefixes.
+pub fn parse_integer(atom: &str) -> Result<i64, Box<dyn std::error::Error>> {
+    let (radix, digits) = match atom.strip_prefix('#') {
+        Some(prefixed) => match prefixed.split_at_checked(1) {
+            Some(("x" | "X", digits)) => (16, digits),
+            Some(("o" | "O", digits)) => (8, digits),
+            Some(("b" | "B", digits)) => (2, digits),
+            _ => {
+                let (radix, digits) = prefixed
+                    .split_once(['r', 'R'])
+                    .ok_or_else(|| format!("Unknown radix prefix in {}.", atom))?;
+                (radix.parse::<u32>()?, digits)
+            }
+        },
+        None => (10, atom.strip_suffix('.').unwrap_or(atom)),
+    };
+    if !(2..=36).contains(&radix) {
+        return Err(format!("Radix {} is out of range in {}.", radix, atom).into());
+    }
+    Ok(i64::from_str_radix(digits, radix)?)
 }
 
 impl<'s> Token<'s> {
+    /// The token itself, without any `#N=` labels wrapped around it.
+    pub fn unlabeled<'p>(&'p self) -> &'p Token<'s> {
+        let mut token = self;
+        while let TokenKind::Labeled(_, labeled) = &token.kind {
+            token = labeled;
+        }
+        token
+    }
+
+    /// What kind of token this is, for error messages. Unlike the `Debug` output this does not grow with the size of the token.
+    fn describe(&self) -> &'static str {
+        match &self.kind {
+            TokenKind::Atom(_) => "an atom",
+            TokenKind::List(_) => "a list",
+            TokenKind::TextWithProperties(_) => "a text with properties",
+            TokenKind::Vector(_) => "a vector",
+            TokenKind::Labeled(..) => "a labeled token",
+            TokenKind::Reference(_) => "a reference",
+            TokenKind::DottedList(..) => "a dotted list",
+            TokenKind::Record(_) => "a record",
+            TokenKind::ByteCode(_) => "a byte-code function",
+            TokenKind::BoolVector { .. } => "a bool-vector",
+            TokenKind::Character(_) => "a character",
+            TokenKind::ReaderMacro(..) => "a quoted token",
+        }
+    }
+
     pub fn as_vector<'p>(&'p self) -> Result<&'p Vec<Token<'s>>, Box<dyn std::error::Error>> {
-        Ok(match self {
-            Token::Vector(children) => Ok(children),
-            _ => Err(format!("wrong token type {:?}", self)),
+        let token = self.unlabeled();
+        Ok(match &token.kind {
+            TokenKind::Vector(children) => Ok(children),
+            _ => Err(format!("Expected a vector, got {}.", token.describe())),
         }?)
     }
 
     pub fn as_list<'p>(&'p self) -> Result<&'p Vec<Token<'s>>, Box<dyn std::error::Error>> {
-        Ok(match self {
-            Token::List(children) => Ok(children),
-            _ => Err(format!("wrong token type {:?}", self)),
+        let token = self.unlabeled();
+        Ok(match &token.kind {
+            TokenKind::List(children) => Ok(children),
+            _ => Err(format!("Expected a list, got {}.", token.describe())),
         }?)
     }
 
     pub fn as_atom<'p>(&'p self) -> Result<&'s str, Box<dyn std::error::Error>> {
-        Ok(match self {
-            Token::Atom(body) => Ok(*body),
-            _ => Err(format!("wrong token type {:?}", self)),
+        let token = self.unlabeled();
+        Ok(match &token.kind {
+            TokenKind::Atom(body) => Ok(*body),
+            _ => Err(format!("Expected an atom, got {}.", token.describe())),
         }?)
     }
 
+    /// The value of an integer atom. See `parse_integer`.
+    pub fn as_integer(&self) -> Result<i64, Box<dyn std::error::Error>> {
+        parse_integer(self.as_atom()?)
+    }
+
     pub fn as_text<'p>(&'p self) -> Result<&'p TextWithProperties<'s>, Box<dyn std::error::Error>> {
-        Ok(match self {
-            Token::TextWithProperties(body) => Ok(body),
-            _ => Err(format!("wrong token type {:?}", self)),
+        let token = self.unlabeled();
+        Ok(match &token.kind {
+            TokenKind::TextWithProperties(body) => Ok(body),
+            _ => Err(format!(
+                "Expected a text with properties, got {}.",
+                token.describe()
+            )),
         }?)
     }
 
-    pub fn as_map<'p>(
+    /// Read a list of alternating keys and values. `owner` names what the properties belong to, like the element type, in errors about them.
+    pub fn as_plist<'p>(
         &'p self,
-    ) -> Result<HashMap<&'s str, &'p Token<'s>>, Box<dyn std::error::Error>> {
-        let mut hashmap = HashMap::new();
-
+        owner: &'p str,
+    ) -> Result<Plist<'p, 's>, Box<dyn std::error::Error>> {
         let children = self.as_list()?;
         if children.len() % 2 != 0 {
-            return Err("Expecting an even number of children".into());
+            return Err(format!("The properties of {} have a key without a value.", owner).into());
+        }
+        Ok(Plist { owner, children })
+    }
+
+    /// Whether this reads as `nil`, which is both the empty list and false.
+    pub fn is_nil(&self) -> bool {
+        match &self.unlabeled().kind {
+            TokenKind::Atom(atom) => *atom == "nil",
+            TokenKind::List(children) => children.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+/// A property list read in order, keeping any duplicate keys.
+///
+/// Lookups find the first occurrence of a key like `plist-get` does. The typed getters treat a missing key and a `nil` value alike, returning `None`, and report a value of the wrong type as an error naming the owner and the key.
+pub struct Plist<'p, 's> {
+    owner: &'p str,
+    children: &'p [Token<'s>],
+}
+
+impl<'p, 's> Plist<'p, 's> {
+    /// Every key and its value, in the order they were printed.
+    pub fn iter(&self) -> impl Iterator<Item = (&'p Token<'s>, &'p Token<'s>)> {
+        self.children.chunks(2).map(|pair| (&pair[0], &pair[1]))
+    }
+
+    /// The keys that appear more than once, each listed once in the order they were first repeated.
+    pub fn duplicate_keys(&self) -> Vec<&'s str> {
+        let mut seen = HashSet::new();
+        let mut duplicates = Vec::new();
+        for (key, _) in self.iter() {
+            if let TokenKind::Atom(key) = key.unlabeled().kind {
+                if !seen.insert(key) && !duplicates.contains(&key) {
+                    duplicates.push(key);
+                }
+            }
         }
-        let mut key: Option<&str> = None;
-        for child in children.iter() {
-            match key {
-                None => {
-                    key = Some(child.as_atom()?);
+        duplicates
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'p Token<'s>> {
+        self.iter()
+            .find(|(candidate, _)| matches!(candidate.unlabeled().kind, TokenKind::Atom(atom) if atom == key))
+            .map(|(_, value)| value)
+    }
+
+    /// Like `get`, but a missing key is an error.
+    #[allow(dead_code)]
+    pub fn require(&self, key: &str) -> Result<&'p Token<'s>, Box<dyn std::error::Error>> {
+        self.get(key).ok_or_else(|| self.missing(key))
+    }
+
+    /// The error for a property that has to be present.
+    pub fn missing(&self, key: &str) -> Box<dyn std::error::Error> {
+        format!("The {} has no {} property.", self.owner, key).into()
+    }
+
+    fn wrong_type(
+        &self,
+        key: &str,
+        expected: &str,
+        value: &Token<'_>,
+    ) -> Box<dyn std::error::Error> {
+        format!(
+            "The {} property of the {} should be {}, got {}.",
+            key,
+            self.owner,
+            expected,
+            value.describe()
+        )
+        .into()
+    }
+
+    fn get_non_nil(&self, key: &str) -> Option<&'p Token<'s>> {
+        self.get(key).filter(|value| !value.is_nil())
+    }
+
+    pub fn get_usize(&self, key: &str) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        self.get_non_nil(key)
+            .map(|value| {
+                value
+                    .as_atom()
+                    .ok()
+                    .and_then(|atom| atom.parse::<usize>().ok())
+                    .ok_or_else(|| self.wrong_type(key, "a non-negative integer", value))
+            })
+            .transpose()
+    }
+
+    /// The unquoted value of a string property. Strings with text properties give just their text.
+    pub fn get_string(&self, key: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        self.get_non_nil(key)
+            .map(|value| match &value.unlabeled().kind {
+                TokenKind::Atom(atom) if atom.starts_with('"') => unquote(atom),
+                TokenKind::TextWithProperties(text) => text.unquote(),
+                _ => Err(self.wrong_type(key, "a string", value)),
+            })
+            .transpose()
+    }
+
+    /// The name of a symbol property, as printed.
+    pub fn get_symbol(&self, key: &str) -> Result<Option<&'s str>, Box<dyn std::error::Error>> {
+        self.get_non_nil(key)
+            .map(|value| match value.unlabeled().kind {
+                TokenKind::Atom(atom) if !atom.starts_with('"') => Ok(atom),
+                _ => Err(self.wrong_type(key, "a symbol", value)),
+            })
+            .transpose()
+    }
+
+    pub fn get_list(
+        &self,
+        key: &str,
+    ) -> Result<Option<&'p [Token<'s>]>, Box<dyn std::error::Error>> {
+        self.get_non_nil(key)
+            .map(|value| match &value.unlabeled().kind {
+                TokenKind::List(children) => Ok(children.as_slice()),
+                _ => Err(self.wrong_type(key, "a list", value)),
+            })
+            .transpose()
+    }
+}
+
+/// The `#N=` labels in a token tree, so `#N#` references can be followed back to the token they refer to.
+///
+/// References are not replaced in the tree itself because the structures printed with `print-circle` (like every element's `:parent`) are usually cyclic.
+pub struct Labels<'p, 's> {
+    labels: HashMap<usize, &'p Token<'s>>,
+}
+
+impl<'p, 's> Labels<'p, 's> {
+    /// Collect every label under `root`, checking that labels are unique and every reference has a label.
+    pub fn collect(root: &'p Token<'s>) -> Result<Labels<'p, 's>, Box<dyn std::error::Error>> {
+        let mut labels = HashMap::new();
+        let mut references = Vec::new();
+        let mut stack = vec![root];
+        while let Some(token) = stack.pop() {
+            match &token.kind {
+                TokenKind::Atom(_) | TokenKind::BoolVector { .. } | TokenKind::Character(_) => {}
+                TokenKind::List(children)
+                | TokenKind::Vector(children)
+                | TokenKind::Record(children)
+                | TokenKind::ByteCode(children) => stack.extend(children),
+                TokenKind::DottedList(children, tail) => {
+                    stack.extend(children);
+                    stack.push(tail);
                 }
-                Some(key_val) => {
-                    key = None;
-                    hashmap.insert(key_val, child);
+                TokenKind::ReaderMacro(_, quoted) => stack.push(quoted),
+                TokenKind::TextWithProperties(text) => stack.extend(&text.properties),
+                TokenKind::Labeled(label, labeled) => {
+                    if labels.insert(*label, labeled.unlabeled()).is_some() {
+                        return Err(format!("Label #{}= is defined more than once.", label).into());
+                    }
+                    stack.push(labeled);
                 }
-            };
+                TokenKind::Reference(label) => references.push(*label),
+            }
+        }
+        if let Some(label) = references
+            .into_iter()
+            .find(|label| !labels.contains_key(label))
+        {
+            return Err(format!("Reference #{}# has no matching #{}= label.", label, label).into());
+        }
+        Ok(Labels { labels })
+    }
+
+    /// Follow a `#N#` reference to the token it refers to. Any other token is returned as is, without its labels.
+    pub fn resolve(
+        &self,
+        token: &'p Token<'s>,
+    ) -> Result<&'p Token<'s>, Box<dyn std::error::Error>> {
+        let token = token.unlabeled();
+        match &token.kind {
+            TokenKind::Reference(label) => self
+                .labels
+                .get(label)
+                .copied()
+                .ok_or_else(|| format!("Reference #{}# has no matching label.", label).into()),
+            _ => Ok(token),
         }
+    }
+}
+
+/// Dropping a token the derived way recurses once per level of nesting, so deeply nested tokens are taken apart onto a stack first.
+impl<'s> Drop for Token<'s> {
+    fn drop(&mut self) {
+        let mut stack = Vec::new();
+        self.take_children(&mut stack);
+        while let Some(mut token) = stack.pop() {
+            token.take_children(&mut stack);
+        }
+    }
+}
+
+impl<'s> Token<'s> {
+    /// Move the tokens this one contains onto `stack`, leaving it with nothing nested inside.
+    fn take_children(&mut self, stack: &mut Vec<Token<'s>>) {
+        let span = self.span;
+        let empty = || Token {
+            kind: TokenKind::List(Vec::new()),
+            span,
+        };
+        match &mut self.kind {
+            TokenKind::Atom(_)
+            | TokenKind::Reference(_)
+            | TokenKind::BoolVector { .. }
+            | TokenKind::Character(_) => {}
+            TokenKind::List(children)
+            | TokenKind::Vector(children)
+            | TokenKind::Record(children)
+            | TokenKind::ByteCode(children) => stack.append(children),
+            TokenKind::DottedList(children, tail) => {
+                stack.append(children);
+                stack.push(std::mem::replace(tail, empty()));
+            }
+            TokenKind::TextWithProperties(text) => stack.append(&mut text.properties),
+            TokenKind::ReaderMacro(_, boxed) | TokenKind::Labeled(_, boxed) => {
+                stack.push(std::mem::replace(boxed, empty()))
+            }
+        }
+    }
+}
+
+/// The deepest nesting of lists, vectors, quotes and labels that `sexp_with_padding` reads before giving up. Use `sexp_with_max_depth` for a different limit.
+///
+/// Reading sexps and building trees from them does not recurse, but serializing the response does, so this stays below `MAX_SAFE_DEPTH`. Org documents nest nowhere near this deep.
+pub const DEFAULT_MAX_DEPTH: usize = 512;
+
+/// The deepest `ORG_INVESTIGATION_MAX_DEPTH` may allow. Serializing a response recurses once per level, and `maximum_depth_serializes` checks that trees this deep fit in a 2MiB thread stack, the size tokio gives its workers, in a debug build.
+pub const MAX_SAFE_DEPTH: usize = 1024;
 
-        Ok(hashmap)
+/// Read the maximum depth from `ORG_INVESTIGATION_MAX_DEPTH`, defaulting to `DEFAULT_MAX_DEPTH`.
+pub fn max_depth_from_env() -> Result<usize, Box<dyn std::error::Error>> {
+    let max_depth = match std::env::var("ORG_INVESTIGATION_MAX_DEPTH") {
+        Ok(val) => val.parse::<usize>()?,
+        Err(_) => DEFAULT_MAX_DEPTH,
+    };
+    if max_depth > MAX_SAFE_DEPTH {
+        return Err(format!(
+            "ORG_INVESTIGATION_MAX_DEPTH must be at most {}, since deeper responses can overflow the stack while being serialized.",
+            MAX_SAFE_DEPTH
+        )
+        .into());
     }
+    Ok(max_depth)
+}
+
+/// How many of the constructs enclosing a parse error are reported, innermost first. An error deep in a deeply nested sexp would otherwise be as large as the sexp.
+const MAX_REPORTED_CONTEXTS: usize = 16;
+
+/// Read all of `input` as a single sexp, failing if anything other than whitespace follows it. `what` describes the sexp in that error, for example "the AST".
+pub fn read_sexp<'s>(
+    input: &'s str,
+    what: &str,
+    max_depth: usize,
+) -> Result<Token<'s>, OrgParseError> {
+    let (remaining, parsed_sexp) = sexp_with_max_depth(input, max_depth)
+        .map_err(|e| OrgParseError::from_sexp_error(input, e))?;
+    if !remaining.is_empty() {
+        return Err(OrgParseError::sexp_at(
+            input,
+            input.len() - remaining.len(),
+            format!("Unexpected input after {}.", what),
+        ));
+    }
+    Ok(parsed_sexp)
 }
 
 #[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
+#[allow(dead_code)]
 pub fn sexp_with_padding<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
+    sexp_with_max_depth(input, DEFAULT_MAX_DEPTH)
+}
+
+/// Like `sexp_with_padding`, but failing on anything nested more than `max_depth` deep.
+pub fn sexp_with_max_depth<'s>(input: &'s str, max_depth: usize) -> Res<&'s str, Token<'s>> {
     let (remaining, _) = multispace0(input)?;
-    let (remaining, tkn) = token(remaining)?;
+    let (remaining, tkn) = expect("an expression", |i| token(i, input, max_depth))(remaining)?;
     let (remaining, _) = multispace0(remaining)?;
     Ok((remaining, tkn))
 }
 
 #[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
+#[allow(dead_code)]
 pub fn sexp<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
-    let (remaining, tkn) = token(input)?;
+    let (remaining, tkn) = token(input, input, DEFAULT_MAX_DEPTH)?;
     Ok((remaining, tkn))
 }
 
-#[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
-fn token<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
-    alt((list, vector, atom))(input)
+/// Run `parser`, turning a failure to match into an unrecoverable error saying what was `expected`. Use this once the input is known to be a particular construct, so the error points at what is wrong inside it instead of at its start.
+fn expect<'s, O, F>(expected: &'static str, mut parser: F) -> impl FnMut(&'s str) -> Res<&'s str, O>
+where
+    F: FnMut(&'s str) -> Res<&'s str, O>,
+{
+    move |input| {
+        parser(input).map_err(|err| match err {
+            nom::Err::Error(_) => nom::Err::Failure(CustomError::MyError(MyError(input, expected))),
+            err => err,
+        })
+    }
 }
 
-#[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
-fn list<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
-    let (remaining, _) = tag("(")(input)?;
-    let (remaining, children) = delimited(
-        multispace0,
-        separated_list1(multispace1, token),
-        multispace0,
-    )(remaining)?;
-    let (remaining, _) = tag(")")(remaining)?;
-    Ok((remaining, Token::List(children)))
+/// A construct `token` has started reading but not finished.
+enum Frame<'s> {
+    /// The elements read so far of a list, vector, record or text with properties. `start` is where it opened.
+    Sequence {
+        start: &'s str,
+        kind: SequenceKind<'s>,
+        children: Vec<Token<'s>>,
+    },
+    /// A list that has reached its `.` and is waiting on the tail.
+    DottedTail {
+        start: &'s str,
+        children: Vec<Token<'s>>,
+    },
+    /// A quote starting at the `&str` waiting on the token it quotes.
+    Quote(&'s str, ReaderMacro),
+    /// A `#N=` label starting at the `&str` waiting on the token it labels.
+    Label(&'s str, usize),
 }
 
-#[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
-fn vector<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
-    let (remaining, _) = tag("[")(input)?;
-    let (remaining, children) = delimited(
-        multispace0,
-        separated_list1(multispace1, token),
-        multispace0,
-    )(remaining)?;
-    let (remaining, _) = tag("]")(remaining)?;
-    Ok((remaining, Token::Vector(children)))
+enum SequenceKind<'s> {
+    List,
+    Vector,
+    Record,
+    ByteCode,
+    /// The properties of a text with properties, holding the source of its string.
+    Text(&'s str),
+}
+
+impl<'s> Frame<'s> {
+    /// What may come next, for the error when nothing that can does.
+    fn expected(&self) -> &'static str {
+        match self {
+            Frame::Sequence {
+                kind: SequenceKind::Vector | SequenceKind::ByteCode,
+                ..
+            } => "an expression or `]`",
+            Frame::Sequence { .. } => "an expression or `)`",
+            Frame::DottedTail { .. } => "an expression after `.`",
+            Frame::Quote(..) => "an expression after the quote",
+            Frame::Label(..) => "an expression after the label",
+        }
+    }
+
+    /// The construct to name as the context of an error inside this frame, and where it starts.
+    fn context(&self) -> Option<(&'s str, &'static str)> {
+        match self {
+            Frame::Sequence { start, kind, .. } => Some((
+                start,
+                match kind {
+                    SequenceKind::List => "list",
+                    SequenceKind::Vector => "vector",
+                    SequenceKind::Record => "record",
+                    SequenceKind::ByteCode => "byte-code function",
+                    SequenceKind::Text(_) => "text with properties",
+                },
+            )),
+            Frame::DottedTail { start, .. } => Some((start, "list")),
+            Frame::Quote(..) | Frame::Label(..) => None,
+        }
+    }
+}
+
+impl<'s> SequenceKind<'s> {
+    fn close(&self) -> &'static str {
+        match self {
+            SequenceKind::Vector | SequenceKind::ByteCode => "]",
+            _ => ")",
+        }
+    }
+
+    fn finish(self, children: Vec<Token<'s>>) -> TokenKind<'s> {
+        match self {
+            SequenceKind::List => TokenKind::List(children),
+            SequenceKind::Vector => TokenKind::Vector(children),
+            SequenceKind::Record => TokenKind::Record(children),
+            SequenceKind::ByteCode => TokenKind::ByteCode(children),
+            SequenceKind::Text(text) => TokenKind::TextWithProperties(TextWithProperties {
+                text,
+                properties: children,
+            }),
+        }
+    }
+}
+
+/// The beginning of a token, which is either the whole token or opens a construct that contains more tokens.
+enum Opened<'s> {
+    Token(TokenKind<'s>),
+    Frame(Frame<'s>),
+}
+
+/// Read one token, keeping the constructs it is nested in on an explicit stack so that deeply nested input cannot overflow the call stack.
+///
+/// Tokens only need whitespace between them where they would otherwise run together, as in `(a"b"(c))`. Spans are byte offsets into `base`, which `input` must be a suffix of.
+fn token<'s>(input: &'s str, base: &'s str, max_depth: usize) -> Res<&'s str, Token<'s>> {
+    let mut stack = Vec::new();
+    _token(input, base, max_depth, &mut stack).map_err(|err| {
+        err.map(|mut err| {
+            // The stack is left as it was when the error happened, so it lists the constructs the error is inside of.
+            for (start, construct) in stack
+                .iter()
+                .rev()
+                .filter_map(Frame::context)
+                .take(MAX_REPORTED_CONTEXTS)
+            {
+                err = CustomError::Context(start, construct, Box::new(err));
+            }
+            err
+        })
+    })
+}
+
+fn _token<'s>(
+    input: &'s str,
+    base: &'s str,
+    max_depth: usize,
+    stack: &mut Vec<Frame<'s>>,
+) -> Res<&'s str, Token<'s>> {
+    // The token from `start` up to `end`, both suffixes of `base`.
+    let token = |kind, start: &str, end: &str| Token {
+        kind,
+        span: Span {
+            start: base.len() - start.len(),
+            end: base.len() - end.len(),
+        },
+    };
+    let mut remaining = input;
+    loop {
+        let (after_open, opened) = match stack.last() {
+            Some(frame) => expect(frame.expected(), open_token)(remaining)?,
+            None => open_token(remaining)?,
+        };
+        let mut finished = match opened {
+            Opened::Token(kind) => Some(token(kind, remaining, after_open)),
+            Opened::Frame(frame) => {
+                if stack.len() >= max_depth {
+                    return Err(nom::Err::Failure(CustomError::TooDeep(
+                        remaining, max_depth,
+                    )));
+                }
+                stack.push(frame);
+                None
+            }
+        };
+        remaining = after_open;
+
+        // Hand each finished token to the construct it is in, closing any constructs that end here, until we reach one that needs another token.
+        loop {
+            if let Some(finished_token) = finished.take() {
+                match stack.pop() {
+                    None => return Ok((remaining, finished_token)),
+                    Some(Frame::Sequence {
+                        start,
+                        kind,
+                        mut children,
+                    }) => {
+                        children.push(finished_token);
+                        stack.push(Frame::Sequence {
+                            start,
+                            kind,
+                            children,
+                        });
+                    }
+                    Some(frame @ Frame::DottedTail { .. }) => {
+                        // The list stays on the stack until its `)` is read so an error there names it.
+                        stack.push(frame);
+                        let (after_space, _) = multispace0(remaining)?;
+                        let (after_close, _) =
+                            expect("`)` after the dotted tail", tag(")"))(after_space)?;
+                        let (start, children) = match stack.pop() {
+                            Some(Frame::DottedTail { start, children }) => (start, children),
+                            _ => unreachable!("The dotted tail frame was just pushed."),
+                        };
+                        remaining = after_close;
+                        finished = Some(token(
+                            TokenKind::DottedList(children, Box::new(finished_token)),
+                            start,
+                            remaining,
+                        ));
+                        continue;
+                    }
+                    Some(Frame::Quote(start, reader_macro)) => {
+                        finished = Some(token(
+                            TokenKind::ReaderMacro(reader_macro, Box::new(finished_token)),
+                            start,
+                            remaining,
+                        ));
+                        continue;
+                    }
+                    Some(Frame::Label(start, label)) => {
+                        finished = Some(token(
+                            TokenKind::Labeled(label, Box::new(finished_token)),
+                            start,
+                            remaining,
+                        ));
+                        continue;
+                    }
+                }
+            }
+            let (kind, children) = match stack.last() {
+                Some(Frame::Sequence { kind, children, .. }) => (kind, children),
+                // Quotes, labels and dotted tails are always waiting on a token.
+                _ => break,
+            };
+            let (after_space, _) = multispace0(remaining)?;
+            if let Ok((after_close, _)) = tag::<_, _, CustomError<&str>>(kind.close())(after_space)
+            {
+                remaining = after_close;
+                finished = match stack.pop() {
+                    Some(Frame::Sequence {
+                        start,
+                        kind,
+                        children,
+                    }) => Some(token(kind.finish(children), start, remaining)),
+                    _ => unreachable!("The top of the stack is a sequence."),
+                };
+                continue;
+            }
+            if matches!(kind, SequenceKind::List) && !children.is_empty() {
+                if let Ok((after_dot, _)) = dot(after_space) {
+                    let (after_space, _) = multispace0(after_dot)?;
+                    remaining = after_space;
+                    match stack.pop() {
+                        Some(Frame::Sequence {
+                            start, children, ..
+                        }) => stack.push(Frame::DottedTail { start, children }),
+                        _ => unreachable!("The top of the stack is a sequence."),
+                    }
+                    break;
+                }
+            }
+            remaining = after_space;
+            break;
+        }
+    }
+}
+
+/// Read a token that contains no other tokens, or the opening of one that does.
+fn open_token<'s>(input: &'s str) -> Res<&'s str, Opened<'s>> {
+    let sequence = |kind| {
+        Opened::Frame(Frame::Sequence {
+            start: input,
+            kind,
+            children: Vec::new(),
+        })
+    };
+    alt((
+        map(tag("("), move |_| sequence(SequenceKind::List)),
+        map(tag("["), move |_| sequence(SequenceKind::Vector)),
+        // Like emacs, allow whitespace between a quote or label and what it applies to.
+        map(terminated(reader_macro, multispace0), |reader_macro| {
+            Opened::Frame(Frame::Quote(input, reader_macro))
+        }),
+        map(text_with_properties, move |text| {
+            sequence(SequenceKind::Text(text))
+        }),
+        map(tag("#s("), move |_| sequence(SequenceKind::Record)),
+        map(tag("#["), move |_| sequence(SequenceKind::ByteCode)),
+        map(terminated(label, multispace0), |label| {
+            Opened::Frame(Frame::Label(input, label))
+        }),
+        map(atom, Opened::Token),
+    ))(input)
+}
+
+/// The `.` of a dotted pair, which unlike the start of a symbol like `.foo` must be followed by a delimiter.
+fn dot(input: &str) -> Res<&str, &str> {
+    let (remaining, dot) = tag(".")(input)?;
+    peek(alt((multispace1, recognize(one_of("()[]\"';`,")))))(remaining)?;
+    Ok((remaining, dot))
 }
 
+fn reader_macro(input: &str) -> Res<&str, ReaderMacro> {
+    alt((
+        map(tag("#'"), |_| ReaderMacro::Function),
+        map(tag("'"), |_| ReaderMacro::Quote),
+        map(tag("`"), |_| ReaderMacro::Backquote),
+        map(tag(",@"), |_| ReaderMacro::Splice),
+        map(tag(","), |_| ReaderMacro::Unquote),
+    ))(input)
+}
+
+/// A token that contains no other tokens.
 #[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
-fn atom<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
+fn atom<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
     not(peek(one_of(")]")))(input)?;
     alt((
-        text_with_properties,
         hash_notation,
+        reference,
+        radix_integer,
+        bool_vector,
+        level_reference,
+        map(tag("##"), TokenKind::Atom),
+        character,
         quoted_atom,
         unquoted_atom,
     ))(input)
 }
 
+/// The `#N=` before a labeled token.
+fn label(input: &str) -> Res<&str, usize> {
+    delimited(tag("#"), label_number, tag("="))(input)
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
+fn reference<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
+    let (remaining, label) = delimited(tag("#"), label_number, tag("#"))(input)?;
+    Ok((remaining, TokenKind::Reference(label)))
+}
+
+fn label_number(input: &str) -> Res<&str, usize> {
+    let (remaining, digits) = digit1(input)?;
+    let label = digits
+        .parse::<usize>()
+        .map_err(|_| nom::Err::Error(CustomError::MyError(MyError(input, "a smaller label"))))?;
+    Ok((remaining, label))
+}
+
+/// `#2`, printed instead of an object that is already being printed two levels up when `print-circle` is nil. Kept as an atom since there is nothing to resolve it to.
+#[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
+fn level_reference<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
+    map(recognize(preceded(tag("#"), digit1)), TokenKind::Atom)(input)
+}
+
+/// `#x1F`, `#o17`, `#b101` or `#24r1k`. Kept as an atom, read the value with `TokenKind::as_integer`.
+#[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
+fn radix_integer<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
+    let (remaining, _) = tag("#")(input)?;
+    let (remaining, _) = alt((
+        recognize(one_of("xXoObB")),
+        recognize(tuple((digit1, one_of("rR")))),
+    ))(remaining)?;
+    let (remaining, _) = tuple((opt(one_of("+-")), alphanumeric1))(remaining)?;
+    Ok((remaining, TokenKind::Atom(get_consumed(input, remaining))))
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
+fn bool_vector<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
+    let (remaining, length) = preceded(tag("#&"), label_number)(input)?;
+    let (remaining, bits) = expect("a string of bits", quoted_atom)(remaining)?;
+    let bits = match bits {
+        TokenKind::Atom(bits) => bits,
+        _ => unreachable!("quoted_atom always returns an atom."),
+    };
+    Ok((remaining, TokenKind::BoolVector { length, bits }))
+}
+
+/// A character literal like `?a`, `?\n` or `?\C-\M-x`. Kept as its source.
+#[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
+fn character<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
+    let (remaining, _) = tag("?")(input)?;
+    let (remaining, _) = expect(
+        "a character after `?`",
+        alt((character_escape, recognize(anychar))),
+    )(remaining)?;
+    Ok((
+        remaining,
+        TokenKind::Character(get_consumed(input, remaining)),
+    ))
+}
+
+/// A backslash escape in a character literal, including any `C-`, `M-`, `^` (and so on) modifier prefixes.
+fn character_escape(input: &str) -> Res<&str, &str> {
+    recognize(preceded(
+        tag("\\"),
+        alt((
+            preceded(
+                tuple((one_of("ACHMSs"), tag("-"))),
+                alt((character_escape, recognize(anychar))),
+            ),
+            preceded(tag("^"), alt((character_escape, recognize(anychar)))),
+            recognize(preceded(tag("x"), hex_digit1)),
+            recognize(preceded(tag("u"), take_while_m_n(4, 4, is_hex_digit))),
+            recognize(preceded(tag("U"), take_while_m_n(8, 8, is_hex_digit))),
+            recognize(delimited(tag("N{"), take_till1(|c| c == '}'), tag("}"))),
+            take_while_m_n(1, 3, |c: char| c.is_digit(8)),
+            recognize(anychar),
+        )),
+    ))(input)
+}
+
+fn is_hex_digit(c: char) -> bool {
+    c.is_ascii_hexdigit()
+}
+
+/// A symbol (or a number, which is spelled the same way). Backslash escapes any character, so `foo\ bar` is a single symbol.
 #[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
-fn unquoted_atom<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
-    let (remaining, body) = take_till1(|c| match c {
-        ' ' | '\t' | '\r' | '\n' | ')' | ']' => true,
-        _ => false,
-    })(input)?;
-    Ok((remaining, Token::Atom(body)))
+fn unquoted_atom<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
+    let (remaining, body) = recognize(many1_count(alt((
+        recognize(preceded(tag("\\"), anychar)),
+        take_till1(|c: char| c <= ' ' || "\\\"';()[]`,".contains(c)),
+    ))))(input)?;
+    Ok((remaining, TokenKind::Atom(body)))
 }
 
 #[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
-fn quoted_atom<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
+fn quoted_atom<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
     let (remaining, _) = tag(r#"""#)(input)?;
-    let (remaining, _) = escaped(
-        take_till1(|c| match c {
-            '\\' | '"' => true,
-            _ => false,
-        }),
+    let (remaining, _) = opt(escaped(
+        take_till1(|c| matches!(c, '\\' | '"')),
         '\\',
-        one_of(r#""n\\"#),
-    )(remaining)?;
-    let (remaining, _) = tag(r#"""#)(remaining)?;
+        anychar,
+    ))(remaining)?;
+    let (remaining, _) = expect("closing `\"`", tag(r#"""#))(remaining)?;
     let source = get_consumed(input, remaining);
-    Ok((remaining, Token::Atom(source)))
+    Ok((remaining, TokenKind::Atom(source)))
 }
 
 #[cfg_attr(feature = "tracing", tracing::instrument(ret, level = "debug"))]
-fn hash_notation<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
+fn hash_notation<'s>(input: &'s str) -> Res<&'s str, TokenKind<'s>> {
     let (remaining, _) = tag("#<")(input)?;
-    let (remaining, _body) = take_till1(|c| match c {
-        '>' => true,
-        _ => false,
-    })(remaining)?;
-    let (remaining, _) = tag(">")(remaining)?;
+    let (remaining, _body) =
+        expect("closing `>`", tuple((take_till1(|c| c == '>'), tag(">"))))(remaining)?;
     let source = get_consumed(input, remaining);
-    Ok((remaining, Token::Atom(source)))
+    Ok((remaining, TokenKind::Atom(source)))
 }
 
-fn text_with_properties<'s>(input: &'s str) -> Res<&'s str, Token<'s>> {
+/// The `#("string"` that opens a text with properties, returning the source of the string. The properties follow as a sequence.
+fn text_with_properties(input: &str) -> Res<&str, &str> {
     let (remaining, _) = tag("#(")(input)?;
-    let (remaining, (text, props)) = delimited(
-        multispace0,
-        tuple((
+    expect(
+        "a string after `#(`",
+        preceded(
+            multispace0,
             map(quoted_atom, |atom| match atom {
-                Token::Atom(body) => body,
+                TokenKind::Atom(body) => body,
                 _ => unreachable!(),
             }),
-            preceded(multispace1, opt(separated_list1(multispace1, token))),
-        )),
-        multispace0,
-    )(remaining)?;
-    let (remaining, _) = tag(")")(remaining)?;
-    Ok((
-        remaining,
-        Token::TextWithProperties(TextWithProperties {
-            text,
-            properties: props.unwrap_or(Vec::new()),
-        }),
-    ))
+        ),
+    )(remaining)
 }
 
 /// Get a slice of the string that was consumed in a parser using the original input to the parser and the remaining input after the parser.
 fn get_consumed<'s>(input: &'s str, remaining: &'s str) -> &'s str {
     assert!(is_slice_of(input, remaining));
-    let source = {
-        let offset = remaining.as_ptr() as usize - input.as_ptr() as usize;
-        &input[..offset]
-    };
-    source
+    let offset = remaining.as_ptr() as usize - input.as_ptr() as usize;
+    &input[..offset]
 }
 
 /// Check if the child string slice is a slice of the parent string slice.
@@ -274,11 +1165,12 @@ mod tests {
         let input = "  (foo bar baz )  ";
         let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
         assert_eq!(remaining, "");
-        assert!(match parsed {
-            Token::Atom(_) => false,
-            Token::List(_) => true,
-            Token::TextWithProperties(_) => false,
-            Token::Vector(_) => false,
+        assert!(match parsed.kind {
+            TokenKind::Atom(_) => false,
+            TokenKind::List(_) => true,
+            TokenKind::TextWithProperties(_) => false,
+            TokenKind::Vector(_) => false,
+            _ => false,
         });
     }
 
@@ -287,19 +1179,20 @@ mod tests {
         let input = r#"  ("foo" bar baz )  "#;
         let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
         assert_eq!(remaining, "");
-        assert!(match parsed {
-            Token::Atom(_) => false,
-            Token::List(_) => true,
-            Token::TextWithProperties(_) => false,
-            Token::Vector(_) => false,
+        assert!(match parsed.kind {
+            TokenKind::Atom(_) => false,
+            TokenKind::List(_) => true,
+            TokenKind::TextWithProperties(_) => false,
+            TokenKind::Vector(_) => false,
+            _ => false,
         });
-        let children = match parsed {
-            Token::List(children) => children,
+        let children = match &parsed.kind {
+            TokenKind::List(children) => children,
             _ => panic!("Should be a list."),
         };
         assert_eq!(
-            match children.first() {
-                Some(Token::Atom(body)) => *body,
+            match children.first().map(|child| &child.kind) {
+                Some(TokenKind::Atom(body)) => *body,
                 _ => panic!("First child should be an atom."),
             },
             r#""foo""#
@@ -311,58 +1204,578 @@ mod tests {
         let input = r#"  (foo "b(a)r" baz )  "#;
         let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
         assert_eq!(remaining, "");
-        assert!(match parsed {
-            Token::List(_) => true,
-            _ => false,
-        });
-        let children = match parsed {
-            Token::List(children) => children,
+        assert!(matches!(parsed.kind, TokenKind::List(_)));
+        let children = match &parsed.kind {
+            TokenKind::List(children) => children,
             _ => panic!("Should be a list."),
         };
         assert_eq!(
-            match children.first() {
-                Some(Token::Atom(body)) => *body,
+            match children.first().map(|child| &child.kind) {
+                Some(TokenKind::Atom(body)) => *body,
                 _ => panic!("First child should be an atom."),
             },
             r#"foo"#
         );
         assert_eq!(
-            match children.iter().nth(1) {
-                Some(Token::Atom(body)) => *body,
+            match children.get(1).map(|child| &child.kind) {
+                Some(TokenKind::Atom(body)) => *body,
                 _ => panic!("Second child should be an atom."),
             },
             r#""b(a)r""#
         );
         assert_eq!(
-            match children.iter().nth(2) {
-                Some(Token::Atom(body)) => *body,
+            match children.get(2).map(|child| &child.kind) {
+                Some(TokenKind::Atom(body)) => *body,
                 _ => panic!("Third child should be an atom."),
             },
             r#"baz"#
         );
     }
 
+    #[test]
+    fn empty_list() {
+        let (remaining, parsed) = sexp_with_padding("(() [])").expect("Parse the input");
+        assert_eq!(remaining, "");
+        let children = parsed.as_list().expect("List");
+        assert!(children[0].as_list().expect("Empty list").is_empty());
+        assert!(children[1].as_vector().expect("Empty vector").is_empty());
+    }
+
+    #[test]
+    fn dotted_pair() {
+        let (remaining, parsed) =
+            sexp_with_padding("((a . 1) (b c . d) (e .f))").expect("Parse the input");
+        assert_eq!(remaining, "");
+        let children = parsed.as_list().expect("List");
+        match &children[0].kind {
+            TokenKind::DottedList(car, cdr) => {
+                assert_eq!(car.len(), 1);
+                assert_eq!(cdr.as_integer().expect("Integer"), 1);
+            }
+            _ => panic!("Should be a dotted pair."),
+        }
+        match &children[1].kind {
+            TokenKind::DottedList(cars, cdr) => {
+                assert_eq!(cars.len(), 2);
+                assert_eq!(cdr.as_atom().expect("Atom"), "d");
+            }
+            _ => panic!("Should be a dotted list."),
+        }
+        // A dot that runs into a symbol is part of the symbol.
+        assert_eq!(
+            children[2].as_list().expect("List")[1]
+                .as_atom()
+                .expect("Atom"),
+            ".f"
+        );
+    }
+
+    #[test]
+    fn reader_macros() {
+        let (remaining, parsed) =
+            sexp_with_padding("('a `(b ,c ,@d) #'e)").expect("Parse the input");
+        assert_eq!(remaining, "");
+        let macros: Vec<ReaderMacro> = parsed
+            .as_list()
+            .expect("List")
+            .iter()
+            .map(|child| match &child.kind {
+                TokenKind::ReaderMacro(reader_macro, _) => *reader_macro,
+                _ => panic!("Should be quoted."),
+            })
+            .collect();
+        assert_eq!(
+            macros,
+            vec![
+                ReaderMacro::Quote,
+                ReaderMacro::Backquote,
+                ReaderMacro::Function
+            ]
+        );
+        let backquoted = match &parsed.as_list().expect("List")[1].kind {
+            TokenKind::ReaderMacro(_, backquoted) => backquoted.as_list().expect("List"),
+            _ => panic!("Should be backquoted."),
+        };
+        assert!(matches!(
+            backquoted[1].kind,
+            TokenKind::ReaderMacro(ReaderMacro::Unquote, _)
+        ));
+        assert!(matches!(
+            backquoted[2].kind,
+            TokenKind::ReaderMacro(ReaderMacro::Splice, _)
+        ));
+
+        // Emacs skips whitespace between a quote and what it quotes.
+        let (remaining, parsed) = sexp_with_padding("(' a #'\n b)").expect("Parse the input");
+        assert_eq!(remaining, "");
+        let quoted: Vec<&str> = parsed
+            .as_list()
+            .expect("List")
+            .iter()
+            .map(|child| match &child.kind {
+                TokenKind::ReaderMacro(_, quoted) => quoted.as_atom().expect("Atom"),
+                _ => panic!("Should be quoted."),
+            })
+            .collect();
+        assert_eq!(quoted, ["a", "b"]);
+        assert!(sexp_with_padding("(' )").is_err());
+    }
+
+    #[test]
+    fn characters() {
+        let (remaining, parsed) = sexp_with_padding(
+            r#"(?a ?\) ?\C-x ?\M-\C-a ?\^? ?\x41 ?\u00e9 ?\N{SNOWMAN} ?\101 ?( ?\s)"#,
+        )
+        .expect("Parse the input");
+        assert_eq!(remaining, "");
+        let characters: Vec<&str> = parsed
+            .as_list()
+            .expect("List")
+            .iter()
+            .map(|child| match &child.kind {
+                TokenKind::Character(body) => *body,
+                _ => panic!("Should be a character."),
+            })
+            .collect();
+        assert_eq!(
+            characters,
+            vec![
+                "?a",
+                r#"?\)"#,
+                r#"?\C-x"#,
+                r#"?\M-\C-a"#,
+                r#"?\^?"#,
+                r#"?\x41"#,
+                r#"?\u00e9"#,
+                r#"?\N{SNOWMAN}"#,
+                r#"?\101"#,
+                "?(",
+                r#"?\s"#
+            ]
+        );
+    }
+
+    #[test]
+    fn records_and_hash_tables() {
+        let input = r#"(#s(foo 1 "two") #s(hash-table size 1 test equal data ("k" v)))"#;
+        let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
+        assert_eq!(remaining, "");
+        let children = parsed.as_list().expect("List");
+        match &children[0].kind {
+            TokenKind::Record(slots) => {
+                assert_eq!(slots[0].as_atom().expect("Atom"), "foo");
+                assert_eq!(slots.len(), 3);
+            }
+            _ => panic!("Should be a record."),
+        }
+        match &children[1].kind {
+            TokenKind::Record(slots) => {
+                assert_eq!(slots[0].as_atom().expect("Atom"), "hash-table");
+                assert_eq!(slots[5].as_atom().expect("Atom"), "data");
+                assert_eq!(slots[6].as_list().expect("Data").len(), 2);
+            }
+            _ => panic!("Should be a hash table."),
+        }
+    }
+
+    #[test]
+    fn byte_code() {
+        let input = r#"(:fn #[(x) "\301\207" [x nil] 1 "Doc."])"#;
+        let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
+        assert_eq!(remaining, "");
+        match &parsed.as_list().expect("List")[1].kind {
+            TokenKind::ByteCode(slots) => {
+                assert_eq!(slots.len(), 5);
+                assert_eq!(slots[0].as_list().expect("Arguments").len(), 1);
+                assert_eq!(slots[2].as_vector().expect("Constants").len(), 2);
+            }
+            _ => panic!("Should be a byte-code function."),
+        }
+        assert!(sexp_with_padding("#[(x) 1").is_err());
+    }
+
+    #[test]
+    fn bool_vector() {
+        // Emacs prints the packed bits as raw characters, here 0b11111.
+        let (remaining, parsed) = sexp_with_padding("#&5\"\x1f\"").expect("Parse the input");
+        assert_eq!(remaining, "");
+        assert!(matches!(
+            parsed.kind,
+            TokenKind::BoolVector {
+                length: 5,
+                bits: "\"\x1f\""
+            }
+        ));
+    }
+
+    #[test]
+    fn radix_integers() {
+        let (remaining, parsed) =
+            sexp_with_padding("(42 -7 1. #x1F #o17 #b101 #24r1k #X-ff)").expect("Parse the input");
+        assert_eq!(remaining, "");
+        let values: Vec<i64> = parsed
+            .as_list()
+            .expect("List")
+            .iter()
+            .map(|child| child.as_integer().expect("Integer"))
+            .collect();
+        assert_eq!(values, vec![42, -7, 1, 31, 15, 5, 44, -255]);
+        let (_, float) = sexp_with_padding("1.5").expect("Parse the input");
+        assert!(float.as_integer().is_err());
+    }
+
+    #[test]
+    fn escaped_symbols() {
+        let (remaining, parsed) = sexp_with_padding(r#"(foo\ bar a\(b\) \12 foo#bar ## quote"s")"#)
+            .expect("Parse the input");
+        assert_eq!(remaining, "");
+        let children = parsed.as_list().expect("List");
+        let atoms: Vec<&str> = children
+            .iter()
+            .map(|child| child.as_atom().expect("Atom"))
+            .collect();
+        assert_eq!(
+            atoms,
+            vec![
+                r#"foo\ bar"#,
+                r#"a\(b\)"#,
+                r#"\12"#,
+                "foo#bar",
+                "##",
+                "quote",
+                r#""s""#
+            ]
+        );
+    }
+
     #[test]
     fn string_containing_escaped_characters() {
         let input = r#"  (foo "\\( x=2 \\)" bar)  "#;
         let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
         assert_eq!(remaining, "");
-        assert!(match parsed {
-            Token::Atom(_) => false,
-            Token::List(_) => true,
-            Token::TextWithProperties(_) => false,
-            Token::Vector(_) => false,
+        assert!(match parsed.kind {
+            TokenKind::Atom(_) => false,
+            TokenKind::List(_) => true,
+            TokenKind::TextWithProperties(_) => false,
+            TokenKind::Vector(_) => false,
+            _ => false,
         });
-        let children = match parsed {
-            Token::List(children) => children,
+        let children = match &parsed.kind {
+            TokenKind::List(children) => children,
             _ => panic!("Should be a list."),
         };
         assert_eq!(
-            match children.get(1) {
-                Some(Token::Atom(body)) => *body,
+            match children.get(1).map(|child| &child.kind) {
+                Some(TokenKind::Atom(body)) => *body,
                 _ => panic!("First child should be an atom."),
             },
             r#""\\( x=2 \\)""#
         )
     }
+
+    #[test]
+    fn string_escapes() {
+        let cases = [
+            (r#""a\tb""#, "a\tb"),
+            (r#""\a\b\d\e\f\n\r\v\s""#, "\x07\x08\x7f\x1b\x0c\n\r\x0b "),
+            (r#""\101\0\12""#, "A\0\n"),
+            (r#""\x41\ 1\x3bb""#, "A1\u{3bb}"),
+            (r#""é\U0001F600""#, "\u{e9}\u{1F600}"),
+            (
+                r#""\N{LATIN SMALL LETTER E WITH ACUTE}\N{U+2603}""#,
+                "\u{e9}\u{2603}",
+            ),
+            (r#""\C-a\^[\^?\C-\M-a""#, "\x01\x1b\x7f\u{81}"),
+            (r#""\M-a\377""#, "\u{e1}\u{ff}"),
+            ("\"one \\\ntwo\"", "one two"),
+            (r#""\(\[\"\\""#, "([\"\\"),
+        ];
+        for (source, expected) in cases {
+            let (remaining, parsed) = sexp_with_padding(source).expect("Parse the input");
+            assert_eq!(remaining, "", "{}", source);
+            assert_eq!(
+                unquote(parsed.as_atom().expect("Atom")).expect("Unquote"),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_string_escapes() {
+        for source in [
+            r#""\x""#,
+            r#""\u12""#,
+            r#""\U00110000""#,
+            r#""\N{NOT A CHARACTER NAME}""#,
+            r#""\C-1""#,
+            r#""\S-a""#,
+            r#""\M-é""#,
+            r#""\""#,
+        ] {
+            assert!(unquote(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn string_escapes_round_trip() {
+        let original: String = (0..0x250u32)
+            .chain([0x2603, 0xFFFD, 0x1F600])
+            .filter_map(char::from_u32)
+            .collect();
+        // Print every character with a different escape in turn so each kind is read back next to every other.
+        let mut printed = String::from("\"");
+        for (i, c) in original.chars().enumerate() {
+            let code = u32::from(c);
+            match i % 6 {
+                0 if code < 0o400 => printed.push_str(&format!("\\{:o}\\ ", code)),
+                1 => printed.push_str(&format!("\\x{:x}\\ ", code)),
+                2 if code <= 0xFFFF => printed.push_str(&format!("\\u{:04x}", code)),
+                3 => printed.push_str(&format!("\\U{:08X}", code)),
+                4 => printed.push_str(&format!("\\N{{U+{:X}}}", code)),
+                _ => printed.push_str(&crate::parse::escape_elisp_string(c.to_string())),
+            }
+        }
+        printed.push('"');
+        let (remaining, parsed) = sexp_with_padding(&printed).expect("Parse the input");
+        assert_eq!(remaining, "");
+        assert_eq!(
+            unquote(parsed.as_atom().expect("Atom")).expect("Unquote"),
+            original
+        );
+    }
+
+    #[test]
+    fn print_circle_labels_and_references() {
+        let input = r#"#1=(org-data nil #2=(section (:parent #1#) #3=(paragraph (:parent #2#) #("foo" 0 3 (:parent #3#)))))"#;
+        let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
+        assert_eq!(remaining, "");
+        let labels = Labels::collect(&parsed).expect("Every reference is labeled");
+
+        let org_data = parsed.as_list().expect("Labels are transparent");
+        let section = &org_data[2];
+        let paragraph = &section.as_list().expect("Section is a list")[2];
+        let text = &paragraph.as_list().expect("Paragraph is a list")[2];
+
+        // Walk back up the cycle through each :parent.
+        let text_parent = labels
+            .resolve(
+                &text.as_text().expect("Plain text").properties[2]
+                    .as_list()
+                    .expect("Properties")[1],
+            )
+            .expect("Resolve paragraph");
+        assert_eq!(
+            text_parent.as_list().expect("List")[0]
+                .as_atom()
+                .expect("Atom"),
+            "paragraph"
+        );
+        let paragraph_parent = labels
+            .resolve(
+                paragraph.as_list().expect("List")[1]
+                    .as_plist("paragraph")
+                    .expect("Plist")
+                    .require(":parent")
+                    .expect("Parent"),
+            )
+            .expect("Resolve section");
+        assert_eq!(
+            paragraph_parent.as_list().expect("List")[0]
+                .as_atom()
+                .expect("Atom"),
+            "section"
+        );
+        let section_parent = labels
+            .resolve(
+                paragraph_parent.as_list().expect("List")[1]
+                    .as_plist("section")
+                    .expect("Plist")
+                    .require(":parent")
+                    .expect("Parent"),
+            )
+            .expect("Resolve org-data");
+        assert_eq!(
+            section_parent.as_list().expect("List")[0]
+                .as_atom()
+                .expect("Atom"),
+            "org-data"
+        );
+        assert!(std::ptr::eq(section_parent, parsed.unlabeled()));
+    }
+
+    #[test]
+    fn print_circle_shared_structure() {
+        // Shared but not cyclic, like a string that appears in two properties.
+        let input = r#"(headline (:raw-value #1="Heading" :title (#1#)))"#;
+        let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
+        assert_eq!(remaining, "");
+        let labels = Labels::collect(&parsed).expect("Every reference is labeled");
+        let properties = parsed.as_list().expect("List")[1]
+            .as_plist("headline")
+            .expect("Plist");
+        let title = &properties.get_list(":title").expect("List").expect("Title")[0];
+        assert_eq!(
+            labels
+                .resolve(title)
+                .expect("Resolve title")
+                .as_atom()
+                .expect("Atom"),
+            r#""Heading""#
+        );
+    }
+
+    #[test]
+    fn print_circle_label_before_whitespace() {
+        let (remaining, parsed) = sexp_with_padding("#1= (a . #1#)").expect("Parse the input");
+        assert_eq!(remaining, "");
+        match &parsed.kind {
+            TokenKind::Labeled(1, labeled) => {
+                assert!(matches!(labeled.kind, TokenKind::DottedList(..)))
+            }
+            _ => panic!("Should be labeled."),
+        }
+        Labels::collect(&parsed).expect("Every reference is labeled");
+    }
+
+    #[test]
+    fn print_circle_invalid_labels() {
+        let (_, dangling) = sexp_with_padding("(foo #1#)").expect("Parse the input");
+        assert!(Labels::collect(&dangling).is_err());
+        let (_, duplicate) = sexp_with_padding("(#1=foo #1=bar)").expect("Parse the input");
+        assert!(Labels::collect(&duplicate).is_err());
+    }
+
+    #[test]
+    fn level_references_are_still_atoms() {
+        // Without print-circle emacs prints "#N" for an object already being printed N levels up.
+        let (remaining, parsed) =
+            sexp_with_padding("(paragraph (:parent #2))").expect("Parse the input");
+        assert_eq!(remaining, "");
+        let properties = parsed.as_list().expect("List")[1]
+            .as_plist("paragraph")
+            .expect("Plist");
+        assert_eq!(
+            properties.get_symbol(":parent").expect("Symbol"),
+            Some("#2")
+        );
+    }
+
+    #[test]
+    fn plist() {
+        let input = r#"(:begin 1 :title #("Foo" 0 3 (face bold)) :tags ("a" "b") :todo-type todo :end nil :begin 2 "key" 3)"#;
+        let (_, parsed) = sexp_with_padding(input).expect("Parse the input");
+        let properties = parsed.as_plist("headline").expect("Plist");
+        let keys: Vec<_> = properties
+            .iter()
+            .map(|(key, _)| key.as_atom().expect("Atom"))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ":begin",
+                ":title",
+                ":tags",
+                ":todo-type",
+                ":end",
+                ":begin",
+                r#""key""#
+            ]
+        );
+        assert_eq!(properties.duplicate_keys(), [":begin"]);
+        assert_eq!(properties.get_usize(":begin").expect("Integer"), Some(1));
+        assert_eq!(properties.get_usize(":end").expect("Nil"), None);
+        assert_eq!(properties.get_usize(":missing").expect("Missing"), None);
+        assert_eq!(
+            properties.get_string(":title").expect("String"),
+            Some("Foo".to_owned())
+        );
+        assert_eq!(
+            properties.get_symbol(":todo-type").expect("Symbol"),
+            Some("todo")
+        );
+        assert_eq!(
+            properties
+                .get_list(":tags")
+                .expect("List")
+                .map(|tags| tags.len()),
+            Some(2)
+        );
+        assert_eq!(
+            properties.get_usize(":title").unwrap_err().to_string(),
+            "The :title property of the headline should be a non-negative integer, got a text with properties."
+        );
+        assert_eq!(
+            properties.require(":level").unwrap_err().to_string(),
+            "The headline has no :level property."
+        );
+
+        let (_, odd) = sexp_with_padding("(:begin 1 :end)").expect("Parse the input");
+        assert!(odd.as_plist("paragraph").is_err());
+    }
+
+    #[test]
+    fn spans() {
+        let input = "  (a \"b c\" [d] 'e #1=#(\"f\" 0 1 (g h)) . #1#)\n";
+        let (_, parsed) = sexp_with_padding(input).expect("Parse the input");
+        let source = |token: &Token<'_>| &input[token.span.start..token.span.end];
+        assert_eq!(source(&parsed), input.trim());
+        let children = match &parsed.kind {
+            TokenKind::DottedList(children, tail) => {
+                assert_eq!(source(tail), "#1#");
+                children
+            }
+            _ => panic!("Should be a dotted list."),
+        };
+        let sources: Vec<&str> = children.iter().map(source).collect();
+        assert_eq!(
+            sources,
+            vec!["a", "\"b c\"", "[d]", "'e", "#1=#(\"f\" 0 1 (g h))"]
+        );
+        let text = children[4].unlabeled();
+        assert_eq!(source(text), "#(\"f\" 0 1 (g h))");
+        let properties = &text.as_text().expect("Text").properties;
+        assert_eq!(source(&properties[2]), "(g h)");
+    }
+
+    #[test]
+    fn deeply_nested() {
+        let depth = 50_000;
+        let input = format!("{}x{}", "(['#1=".repeat(depth / 4), "])".repeat(depth / 4));
+        let parsed = read_sexp(&input, "the input", depth).expect("Parse the input");
+        let mut levels = 0;
+        let mut token = &parsed;
+        loop {
+            token = match &token.kind {
+                TokenKind::List(children) | TokenKind::Vector(children) => &children[0],
+                TokenKind::ReaderMacro(_, quoted) | TokenKind::Labeled(_, quoted) => quoted,
+                _ => break,
+            };
+            levels += 1;
+        }
+        assert_eq!(levels, depth);
+        assert_eq!(token.kind, TokenKind::Atom("x"));
+    }
+
+    #[test]
+    fn maximum_depth() {
+        let input = format!("{}{}", "(".repeat(100), ")".repeat(100));
+        assert!(read_sexp(&input, "the input", 100).is_ok());
+        let err = read_sexp(&input, "the input", 99).expect_err("Too deep");
+        match err {
+            OrgParseError::Sexp {
+                position,
+                message,
+                context,
+                ..
+            } => {
+                assert_eq!(position, 99);
+                assert_eq!(message, "Nesting exceeds the maximum depth of 99.");
+                assert_eq!(context.len(), MAX_REPORTED_CONTEXTS);
+                assert_eq!(context.last().expect("Context").column, 99);
+            }
+            _ => panic!("Expected a sexp error, got {:?}", err),
+        }
+    }
 }
@@ -0,0 +1,435 @@
+use std::collections::BTreeMap;
+use std::num::IntErrorKind;
+use std::num::ParseIntError;
+
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::error::OrgParseError;
+use crate::parse::EmacsOutput;
+use crate::sexp::parse_integer;
+use crate::sexp::read_sexp;
+use crate::sexp::unquote;
+use crate::sexp::ReaderMacro;
+use crate::sexp::Token;
+use crate::sexp::TokenKind;
+
+/// The raw emacs AST as JSON, returned by `/parse?format=json-sexp`.
+#[derive(Serialize)]
+pub struct JsonSexpTree {
+    input: String,
+    ast: Value,
+    warnings: Vec<String>,
+    /// The printed elisp value of each `OrgSettings` variable in effect during the parse.
+    settings: BTreeMap<String, String>,
+}
+
+pub fn build_json_sexp_tree(
+    body: &str,
+    emacs_output: &EmacsOutput,
+    max_depth: usize,
+) -> Result<JsonSexpTree, OrgParseError> {
+    let ast_raw = emacs_output.ast.as_str();
+    let parsed_sexp = read_sexp(ast_raw, "the AST", max_depth)?;
+    let ast = token_to_json(&parsed_sexp).map_err(|e| OrgParseError::JsonSexp {
+        message: e.to_string(),
+    })?;
+    Ok(JsonSexpTree {
+        input: body.to_owned(),
+        ast,
+        warnings: emacs_output.warnings.clone(),
+        settings: emacs_output.effective_settings.clone(),
+    })
+}
+
+/// Convert a token into JSON without losing anything the printed representation says.
+///
+/// Every value is an object with a `type`:
+///
+/// - `nil` for `nil` and `()`.
+/// - `symbol` with the `name` after removing backslash escapes. Keywords keep their leading colon.
+/// - `string` with its unescaped `value`.
+/// - `integer` and `float` with a numeric `value`. Integers that do not fit in 64 bits, which emacs reads as bignums, have their `source` instead.
+/// - `character` with its `source`, such as `?\C-a`.
+/// - `list` with its `elements`, and a `tail` if it is a dotted list.
+/// - `plist` for a non-empty list alternating between keywords and values, with its `properties` as an ordered array of `{"key": ":begin", "value": ...}`.
+/// - `vector`, `record` (which includes hash tables) and `byte-code` with their `elements`.
+/// - `text` for a string with text properties, with its `value` and `properties` as an array of `{"start": 0, "end": 3, "properties": <plist>}`.
+/// - `bool-vector` with its `length` and the unescaped `bits` string.
+/// - `quote` with the `macro` (`quote`, `backquote`, `unquote`, `splice` or `function`) and the quoted `value`.
+/// - `labeled` with the `label` and `value` of `#N=`, and `reference` with the `label` of `#N#`. References are not resolved since they are usually cycles.
+/// - `level-reference` with the number of `levels` of a `#N` printed instead of an object already being printed.
+/// - `unreadable` with the `source` of a `#<...>` object.
+///
+/// Nested tokens are converted with an explicit stack so deeply nested tokens cannot overflow the call stack.
+pub fn token_to_json(token: &Token<'_>) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut steps = vec![Step::Enter(token)];
+    // The converted tokens whose parents have not been converted yet.
+    let mut values = Vec::new();
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Enter(token) => {
+                let nested = nested_tokens(token)?;
+                steps.push(Step::Exit(token, nested.len()));
+                steps.extend(nested.into_iter().rev().map(Step::Enter));
+            }
+            Step::Exit(token, count) => {
+                let nested = values.split_off(values.len() - count);
+                values.push(convert(token, nested)?);
+            }
+        }
+    }
+    Ok(values
+        .pop()
+        .expect("The outermost token is converted last."))
+}
+
+enum Step<'p, 's> {
+    /// Convert the tokens nested inside this one.
+    Enter(&'p Token<'s>),
+    /// Convert this token from the last `usize` converted values, which are its nested tokens.
+    Exit(&'p Token<'s>, usize),
+}
+
+/// The tokens whose converted values go into the conversion of `token`, in order. Keys of property lists are not included since they become plain strings.
+fn nested_tokens<'p, 's>(
+    token: &'p Token<'s>,
+) -> Result<Vec<&'p Token<'s>>, Box<dyn std::error::Error>> {
+    Ok(match &token.kind {
+        TokenKind::List(children) if is_plist(children) => plist_values(children)?,
+        TokenKind::List(children)
+        | TokenKind::Vector(children)
+        | TokenKind::Record(children)
+        | TokenKind::ByteCode(children) => children.iter().collect(),
+        TokenKind::DottedList(children, tail) => children
+            .iter()
+            .chain(std::iter::once(tail.as_ref()))
+            .collect(),
+        TokenKind::TextWithProperties(text) => {
+            if !text.properties.len().is_multiple_of(3) {
+                return Err(format!(
+                    "Text properties of {} are not in start, end, plist triples.",
+                    text.text
+                )
+                .into());
+            }
+            let mut nested = Vec::new();
+            for triple in text.properties.chunks(3) {
+                match &triple[2].kind {
+                    TokenKind::List(plist) if plist.len().is_multiple_of(2) => {
+                        nested.extend(plist_values(plist)?)
+                    }
+                    other => {
+                        return Err(format!("Text properties {:?} are not a plist.", other).into())
+                    }
+                }
+            }
+            nested
+        }
+        TokenKind::ReaderMacro(_, quoted) | TokenKind::Labeled(_, quoted) => vec![quoted.as_ref()],
+        TokenKind::Atom(_)
+        | TokenKind::BoolVector { .. }
+        | TokenKind::Character(_)
+        | TokenKind::Reference(_) => Vec::new(),
+    })
+}
+
+/// Convert a token whose `nested_tokens` have already been converted to `nested`.
+fn convert(token: &Token<'_>, nested: Vec<Value>) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut nested = nested.into_iter();
+    Ok(match &token.kind {
+        TokenKind::Atom(atom) => atom_to_json(atom)?,
+        TokenKind::List(children) if children.is_empty() => json!({"type": "nil"}),
+        TokenKind::List(children) if is_plist(children) => object([
+            ("type", json!("plist")),
+            ("properties", plist_to_json(children, &mut nested)?),
+        ]),
+        TokenKind::List(_) => object([
+            ("type", json!("list")),
+            ("elements", Value::Array(nested.collect())),
+        ]),
+        TokenKind::DottedList(..) => {
+            let tail = nested.next_back().expect("A dotted list has a tail.");
+            object([
+                ("type", json!("list")),
+                ("elements", Value::Array(nested.collect())),
+                ("tail", tail),
+            ])
+        }
+        TokenKind::Vector(_) => object([
+            ("type", json!("vector")),
+            ("elements", Value::Array(nested.collect())),
+        ]),
+        TokenKind::Record(_) => object([
+            ("type", json!("record")),
+            ("elements", Value::Array(nested.collect())),
+        ]),
+        TokenKind::ByteCode(_) => object([
+            ("type", json!("byte-code")),
+            ("elements", Value::Array(nested.collect())),
+        ]),
+        TokenKind::TextWithProperties(text) => {
+            let properties = text
+                .properties
+                .chunks(3)
+                .map(|triple| {
+                    Ok(object([
+                        ("start", json!(triple[0].as_integer()?)),
+                        ("end", json!(triple[1].as_integer()?)),
+                        (
+                            "properties",
+                            plist_to_json(triple[2].as_list()?, &mut nested)?,
+                        ),
+                    ]))
+                })
+                .collect::<Result<Vec<Value>, Box<dyn std::error::Error>>>()?;
+            object([
+                ("type", json!("text")),
+                ("value", json!(unquote(text.text)?)),
+                ("properties", Value::Array(properties)),
+            ])
+        }
+        TokenKind::BoolVector { length, bits } => json!({
+            "type": "bool-vector",
+            "length": length,
+            "bits": unquote(bits)?,
+        }),
+        TokenKind::Character(source) => json!({"type": "character", "source": source}),
+        TokenKind::ReaderMacro(reader_macro, _) => object([
+            ("type", json!("quote")),
+            (
+                "macro",
+                json!(match reader_macro {
+                    ReaderMacro::Quote => "quote",
+                    ReaderMacro::Backquote => "backquote",
+                    ReaderMacro::Unquote => "unquote",
+                    ReaderMacro::Splice => "splice",
+                    ReaderMacro::Function => "function",
+                }),
+            ),
+            ("value", nested.next().expect("A quote has a value.")),
+        ]),
+        TokenKind::Labeled(label, _) => object([
+            ("type", json!("labeled")),
+            ("label", json!(label)),
+            ("value", nested.next().expect("A label has a value.")),
+        ]),
+        TokenKind::Reference(label) => json!({"type": "reference", "label": label}),
+    })
+}
+
+/// Build an object from values that are already JSON. Unlike `json!`, this moves the values instead of serializing a copy of them.
+fn object<const N: usize>(fields: [(&str, Value); N]) -> Value {
+    Value::Object(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value))
+            .collect(),
+    )
+}
+
+/// Whether a list looks like an org-element property list, alternating between keywords and values.
+fn is_plist(children: &[Token<'_>]) -> bool {
+    children.len().is_multiple_of(2)
+        && children.iter().step_by(2).all(
+            |key| matches!(key.kind, TokenKind::Atom(key) if key.starts_with(':') && key.len() > 1),
+        )
+}
+
+/// The values of alternating keys and values, checking that every key is a symbol. Any symbol may be a key, which text properties rely on.
+fn plist_values<'p, 's>(
+    children: &'p [Token<'s>],
+) -> Result<Vec<&'p Token<'s>>, Box<dyn std::error::Error>> {
+    children
+        .chunks(2)
+        .map(|pair| match &pair[0].kind {
+            TokenKind::Atom(key) if !key.starts_with('"') => Ok(&pair[1]),
+            other => Err(format!("Property key {:?} is not a symbol.", other).into()),
+        })
+        .collect()
+}
+
+/// Pair the keys of a plist checked by `plist_values` with its converted values.
+fn plist_to_json(
+    children: &[Token<'_>],
+    values: &mut impl Iterator<Item = Value>,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    children
+        .chunks(2)
+        .map(|pair| {
+            let key = unescape_symbol(pair[0].as_atom()?);
+            let value = values.next().expect("Every key has a converted value.");
+            Ok(object([("key", json!(key)), ("value", value)]))
+        })
+        .collect::<Result<Vec<Value>, Box<dyn std::error::Error>>>()
+        .map(Value::Array)
+}
+
+fn atom_to_json(atom: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    if atom == "nil" {
+        return Ok(json!({"type": "nil"}));
+    }
+    if atom.starts_with('"') {
+        return Ok(json!({"type": "string", "value": unquote(atom)?}));
+    }
+    if atom.starts_with("#<") {
+        return Ok(json!({"type": "unreadable", "source": atom}));
+    }
+    if atom == "##" {
+        return Ok(json!({"type": "symbol", "name": ""}));
+    }
+    if let Some(levels) = atom.strip_prefix('#') {
+        if let Ok(levels) = levels.parse::<usize>() {
+            return Ok(json!({"type": "level-reference", "levels": levels}));
+        }
+        if let Some(integer) = integer_to_json(atom) {
+            return Ok(integer);
+        }
+    }
+    if is_decimal_integer(atom) {
+        return integer_to_json(atom).ok_or_else(|| format!("Invalid integer {}.", atom).into());
+    }
+    if is_float(atom) {
+        if let Ok(value) = atom.parse::<f64>() {
+            return Ok(json!({"type": "float", "value": value}));
+        }
+    }
+    Ok(json!({"type": "symbol", "name": unescape_symbol(atom)}))
+}
+
+/// An integer atom, or `None` if it is not one.
+fn integer_to_json(atom: &str) -> Option<Value> {
+    match parse_integer(atom) {
+        Ok(value) => Some(json!({"type": "integer", "value": value})),
+        Err(e) if is_overflow(e.as_ref()) => Some(json!({"type": "integer", "source": atom})),
+        Err(_) => None,
+    }
+}
+
+fn is_overflow(error: &(dyn std::error::Error + 'static)) -> bool {
+    let kind = error
+        .downcast_ref::<ParseIntError>()
+        .map(|error| error.kind());
+    matches!(
+        kind,
+        Some(IntErrorKind::PosOverflow | IntErrorKind::NegOverflow)
+    )
+}
+
+/// `1`, `-1` or `1.`, which the reader treats as integers rather than symbols.
+fn is_decimal_integer(atom: &str) -> bool {
+    let digits = atom.strip_prefix(['+', '-']).unwrap_or(atom);
+    let digits = digits.strip_suffix('.').unwrap_or(digits);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Numbers with a fraction or exponent. Emacs' `1.0e+INF` and `0.0e+NaN` have no JSON representation and are left as symbols.
+fn is_float(atom: &str) -> bool {
+    let unsigned = atom.strip_prefix(['+', '-']).unwrap_or(atom);
+    unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        && unsigned.contains(|c: char| c.is_ascii_digit())
+        && unsigned
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'))
+}
+
+fn unescape_symbol(atom: &str) -> String {
+    let mut name = String::with_capacity(atom.len());
+    let mut chars = atom.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.extend(chars.next()),
+            _ => name.push(c),
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sexp::sexp_with_padding;
+
+    fn convert(input: &str) -> Value {
+        let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
+        assert_eq!(remaining, "");
+        token_to_json(&parsed).expect("Convert to JSON")
+    }
+
+    #[test]
+    fn atoms() {
+        assert_eq!(
+            convert(r#"(nil foo\ bar :begin "a\tb" 42 #x1F 1.5 ?a ## #<marker in foo> #2)"#),
+            json!({"type": "list", "elements": [
+                {"type": "nil"},
+                {"type": "symbol", "name": "foo bar"},
+                {"type": "symbol", "name": ":begin"},
+                {"type": "string", "value": "a\tb"},
+                {"type": "integer", "value": 42},
+                {"type": "integer", "value": 31},
+                {"type": "float", "value": 1.5},
+                {"type": "character", "source": "?a"},
+                {"type": "symbol", "name": ""},
+                {"type": "unreadable", "source": "#<marker in foo>"},
+                {"type": "level-reference", "levels": 2},
+            ]})
+        );
+    }
+
+    #[test]
+    fn bignums() {
+        assert_eq!(
+            convert("(9223372036854775807 -99999999999999999999 #x1ffffffffffffffff)"),
+            json!({"type": "list", "elements": [
+                {"type": "integer", "value": 9223372036854775807i64},
+                {"type": "integer", "source": "-99999999999999999999"},
+                {"type": "integer", "source": "#x1ffffffffffffffff"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn element() {
+        let input = r#"(paragraph (:begin 1 :end 5 :parent #1#) #("foo" 0 3 (face bold :parent #1#)) [a (b . c)])"#;
+        assert_eq!(
+            convert(input),
+            json!({"type": "list", "elements": [
+                {"type": "symbol", "name": "paragraph"},
+                {"type": "plist", "properties": [
+                    {"key": ":begin", "value": {"type": "integer", "value": 1}},
+                    {"key": ":end", "value": {"type": "integer", "value": 5}},
+                    {"key": ":parent", "value": {"type": "reference", "label": 1}},
+                ]},
+                {"type": "text", "value": "foo", "properties": [
+                    {"start": 0, "end": 3, "properties": [
+                        {"key": "face", "value": {"type": "symbol", "name": "bold"}},
+                        {"key": ":parent", "value": {"type": "reference", "label": 1}},
+                    ]},
+                ]},
+                {"type": "vector", "elements": [
+                    {"type": "symbol", "name": "a"},
+                    {"type": "list",
+                     "elements": [{"type": "symbol", "name": "b"}],
+                     "tail": {"type": "symbol", "name": "c"}},
+                ]},
+            ]})
+        );
+    }
+
+    #[test]
+    fn reader_syntax() {
+        assert_eq!(
+            convert(r#"(#'car #1=#s(foo 1) #&3"\7")"#),
+            json!({"type": "list", "elements": [
+                {"type": "quote", "macro": "function",
+                 "value": {"type": "symbol", "name": "car"}},
+                {"type": "labeled", "label": 1, "value": {"type": "record", "elements": [
+                    {"type": "symbol", "name": "foo"},
+                    {"type": "integer", "value": 1},
+                ]}},
+                {"type": "bool-vector", "length": 3, "bits": "\x07"},
+            ]})
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use crate::sexp::ReaderMacro;
+use crate::sexp::Token;
+use crate::sexp::TokenKind;
+
+/// The column `pp` tries to keep lines within, the default `fill-column`.
+const PRETTY_WIDTH: usize = 70;
+
+/// Print a token on a single line (apart from newlines inside strings), with one space between elements.
+///
+/// Atoms are printed from their original source so the output reads back to an equal `Token`.
+pub fn print_compact(token: &Token<'_>) -> String {
+    let mut printer = Printer::new(None);
+    printer.token(token);
+    printer.output
+}
+
+/// Print a token indented like `pp`. Lists that fit within the fill column stay on one line, otherwise each element goes on its own line lined up under the first, keeping keywords on the same line as their values.
+#[allow(dead_code)]
+pub fn print_pretty(token: &Token<'_>) -> String {
+    let mut printer = Printer::new(Some(PRETTY_WIDTH));
+    printer.token(token);
+    printer.output
+}
+
+struct Printer {
+    output: String,
+    column: usize,
+    /// Break lists that would run past this column, or never if `None`.
+    width: Option<usize>,
+}
+
+impl Printer {
+    fn new(width: Option<usize>) -> Printer {
+        Printer {
+            output: String::new(),
+            column: 0,
+            width,
+        }
+    }
+
+    fn push(&mut self, text: &str) {
+        self.output.push_str(text);
+        self.column = match text.rfind('\n') {
+            Some(newline) => text[newline + 1..].chars().count(),
+            None => self.column + text.chars().count(),
+        };
+    }
+
+    fn newline(&mut self, indent: usize) {
+        self.output.push('\n');
+        self.output.extend(std::iter::repeat_n(' ', indent));
+        self.column = indent;
+    }
+
+    fn token(&mut self, token: &Token<'_>) {
+        match &token.kind {
+            TokenKind::Atom(source) | TokenKind::Character(source) => self.push(source),
+            TokenKind::List(children) => self.sequence(token, "(", None, children, None, ")"),
+            TokenKind::DottedList(children, tail) => {
+                self.sequence(token, "(", None, children, Some(tail), ")")
+            }
+            TokenKind::Vector(children) => self.sequence(token, "[", None, children, None, "]"),
+            TokenKind::Record(children) => self.sequence(token, "#s(", None, children, None, ")"),
+            TokenKind::ByteCode(children) => self.sequence(token, "#[", None, children, None, "]"),
+            TokenKind::TextWithProperties(text) => {
+                self.sequence(token, "#(", Some(text.text), &text.properties, None, ")")
+            }
+            TokenKind::BoolVector { length, bits } => {
+                self.push(&format!("#&{}", length));
+                self.push(bits);
+            }
+            TokenKind::ReaderMacro(reader_macro, quoted) => {
+                self.push(reader_macro_prefix(reader_macro));
+                self.token(quoted);
+            }
+            TokenKind::Labeled(label, labeled) => {
+                self.push(&format!("#{}=", label));
+                self.token(labeled);
+            }
+            TokenKind::Reference(label) => self.push(&format!("#{}#", label)),
+        }
+    }
+
+    /// Print the elements of a list-like token between `open` and `close`. `leading` is source printed before the elements, which is the string of a text with properties.
+    fn sequence(
+        &mut self,
+        token: &Token<'_>,
+        open: &str,
+        leading: Option<&str>,
+        children: &[Token<'_>],
+        tail: Option<&Token<'_>>,
+        close: &str,
+    ) {
+        if !self.needs_break(token) {
+            self.push(open);
+            let mut first = true;
+            if let Some(leading) = leading {
+                self.push(leading);
+                first = false;
+            }
+            for child in children {
+                if !first {
+                    self.push(" ");
+                }
+                self.token(child);
+                first = false;
+            }
+            if let Some(tail) = tail {
+                self.push(" . ");
+                self.token(tail);
+            }
+            self.push(close);
+            return;
+        }
+
+        self.push(open);
+        let indent = self.column;
+        let mut first = true;
+        if let Some(leading) = leading {
+            self.push(leading);
+            first = false;
+        }
+        let mut children = children.iter();
+        while let Some(child) = children.next() {
+            if !first {
+                self.newline(indent);
+            }
+            self.token(child);
+            if is_keyword(child) {
+                if let Some(value) = children.next() {
+                    self.push(" ");
+                    self.token(value);
+                }
+            }
+            first = false;
+        }
+        if let Some(tail) = tail {
+            self.newline(indent);
+            self.push(". ");
+            self.token(tail);
+        }
+        self.push(close);
+    }
+
+    /// Whether the token would run past the fill column if printed compactly from here.
+    fn needs_break(&self, token: &Token<'_>) -> bool {
+        match self.width {
+            None => false,
+            Some(width) => !fits(token, &mut width.saturating_sub(self.column)),
+        }
+    }
+}
+
+/// Whether the token fits on one line within `budget` columns when printed compactly, taking what it uses from `budget`.
+///
+/// Measuring stops as soon as the budget runs out, so each list is only measured up to the fill column rather than in full at every level of nesting.
+fn fits(token: &Token<'_>, budget: &mut usize) -> bool {
+    match &token.kind {
+        TokenKind::Atom(source) | TokenKind::Character(source) => take(source, budget),
+        TokenKind::List(children) => fits_sequence("(", None, children, None, ")", budget),
+        TokenKind::DottedList(children, tail) => {
+            fits_sequence("(", None, children, Some(tail), ")", budget)
+        }
+        TokenKind::Vector(children) => fits_sequence("[", None, children, None, "]", budget),
+        TokenKind::Record(children) => fits_sequence("#s(", None, children, None, ")", budget),
+        TokenKind::ByteCode(children) => fits_sequence("#[", None, children, None, "]", budget),
+        TokenKind::TextWithProperties(text) => {
+            fits_sequence("#(", Some(text.text), &text.properties, None, ")", budget)
+        }
+        TokenKind::BoolVector { length, bits } => {
+            take(&format!("#&{}", length), budget) && take(bits, budget)
+        }
+        TokenKind::ReaderMacro(reader_macro, quoted) => {
+            take(reader_macro_prefix(reader_macro), budget) && fits(quoted, budget)
+        }
+        TokenKind::Labeled(label, labeled) => {
+            take(&format!("#{}=", label), budget) && fits(labeled, budget)
+        }
+        TokenKind::Reference(label) => take(&format!("#{}#", label), budget),
+    }
+}
+
+/// Measure a list-like token the way `Printer::sequence` prints it on one line.
+fn fits_sequence(
+    open: &str,
+    leading: Option<&str>,
+    children: &[Token<'_>],
+    tail: Option<&Token<'_>>,
+    close: &str,
+    budget: &mut usize,
+) -> bool {
+    if !take(open, budget) {
+        return false;
+    }
+    let mut first = true;
+    if let Some(leading) = leading {
+        if !take(leading, budget) {
+            return false;
+        }
+        first = false;
+    }
+    for child in children {
+        if (!first && !take(" ", budget)) || !fits(child, budget) {
+            return false;
+        }
+        first = false;
+    }
+    if let Some(tail) = tail {
+        if !take(" . ", budget) || !fits(tail, budget) {
+            return false;
+        }
+    }
+    take(close, budget)
+}
+
+/// Take the width of `text` from `budget`, failing if it does not fit or holds a newline.
+fn take(text: &str, budget: &mut usize) -> bool {
+    if text.contains('\n') {
+        return false;
+    }
+    match budget.checked_sub(text.chars().count()) {
+        Some(remaining) => {
+            *budget = remaining;
+            true
+        }
+        None => false,
+    }
+}
+
+fn reader_macro_prefix(reader_macro: &ReaderMacro) -> &'static str {
+    match reader_macro {
+        ReaderMacro::Quote => "'",
+        ReaderMacro::Backquote => "`",
+        ReaderMacro::Unquote => ",",
+        ReaderMacro::Splice => ",@",
+        ReaderMacro::Function => "#'",
+    }
+}
+
+fn is_keyword(token: &Token<'_>) -> bool {
+    matches!(token.kind, TokenKind::Atom(atom) if atom.starts_with(':') && atom.len() > 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::sexp::sexp_with_padding;
+
+    fn parse(input: &str) -> Token<'_> {
+        let (remaining, parsed) = sexp_with_padding(input).expect("Parse the input");
+        assert_eq!(remaining, "", "{}", input);
+        parsed
+    }
+
+    #[test]
+    fn compact() {
+        let input = "(a   b\n  . c)";
+        assert_eq!(print_compact(&parse(input)), "(a b . c)");
+        let input = r#"( #1=#s( foo  [1 2] ) '#1#  #("x" 0 1 (face bold)) #&3"\7" ?\C-a ())"#;
+        assert_eq!(
+            print_compact(&parse(input)),
+            r#"(#1=#s(foo [1 2]) '#1# #("x" 0 1 (face bold)) #&3"\7" ?\C-a ())"#
+        );
+    }
+
+    #[test]
+    fn pretty() {
+        let input = "(section (:begin 1 :end 40 :contents-begin 1 :contents-end 40 :post-blank 0 :parent nil) (paragraph (:begin 1 :end 40) \"foo\"))";
+        assert_eq!(
+            print_pretty(&parse(input)),
+            r#"(section
+ (:begin 1
+  :end 40
+  :contents-begin 1
+  :contents-end 40
+  :post-blank 0
+  :parent nil)
+ (paragraph (:begin 1 :end 40) "foo"))"#
+        );
+    }
+
+    #[test]
+    fn pretty_recorded_ast() {
+        let fixture = include_str!("../fixtures/3423db14172349f8.json");
+        let fixture: serde_json::Value = serde_json::from_str(fixture).expect("Read fixture");
+        let ast = fixture["output"]["ast"].as_str().expect("Recorded AST");
+        let parsed = parse(ast);
+        let pretty = print_pretty(&parsed);
+        assert_eq!(parse(&pretty), parsed);
+        assert!(pretty
+            .lines()
+            .filter(|line| !line.starts_with("\" "))
+            .all(|line| line.chars().count() <= PRETTY_WIDTH));
+    }
+
+    /// Printed sexps covering every kind of token, as emacs would print them.
+    fn arbitrary_sexp() -> impl Strategy<Value = String> {
+        let atom = prop_oneof![
+            "[a-z][a-z0-9-]{0,8}",
+            ":[a-zA-Z-]{1,8}",
+            "-?[0-9]{1,6}",
+            "[0-9]{1,3}\\.[0-9]{1,3}",
+            "#x[0-9a-f]{1,4}",
+            "nil",
+            "##",
+            "#<buffer [a-z ]{1,6}>",
+            "#[1-9]",
+            "#[1-9]#",
+            "[a-z]{1,3}\\\\ [a-z]{1,3}",
+            "\"([a-z ()\\[\\]\n]|\\\\[nt\"\\\\]|\\\\x41\\\\ |\\\\N\\{U\\+2603\\})*\"",
+            "\\?([a-z()]|\\\\[()ntC]|\\\\C-[a-z]|\\\\\\^\\?)",
+            "#&[0-9]\"[a-z]{0,2}\"",
+        ];
+        atom.prop_recursive(6, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8)
+                    .prop_map(|children| format!("({})", children.join(" "))),
+                prop::collection::vec(inner.clone(), 0..8)
+                    .prop_map(|children| format!("[{}]", children.join(" "))),
+                prop::collection::vec(inner.clone(), 1..4)
+                    .prop_map(|children| format!("#s({})", children.join(" "))),
+                prop::collection::vec(inner.clone(), 1..4)
+                    .prop_map(|children| format!("#[{}]", children.join(" "))),
+                (prop::collection::vec(inner.clone(), 1..4), inner.clone())
+                    .prop_map(|(children, tail)| format!("({} . {})", children.join(" "), tail)),
+                (prop_oneof!["'", "`", ",", ",@", "#'"], inner.clone())
+                    .prop_map(|(prefix, quoted)| format!("{}{}", prefix, quoted)),
+                (1..10usize, inner.clone())
+                    .prop_map(|(label, labeled)| format!("#{}={}", label, labeled)),
+                ("[a-z]{1,6}", inner.clone(), inner).prop_map(|(text, key, value)| format!(
+                    "#(\"{}\" 0 {} (face {} :parent {}))",
+                    text,
+                    text.len(),
+                    key,
+                    value
+                )),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn compact_round_trip(input in arbitrary_sexp()) {
+            let parsed = parse(&input);
+            let printed = print_compact(&parsed);
+            let reparsed = parse(&printed);
+            prop_assert_eq!(&reparsed, &parsed);
+            prop_assert_eq!(&print_compact(&reparsed), &printed);
+        }
+
+        #[test]
+        fn pretty_round_trip(input in arbitrary_sexp()) {
+            let parsed = parse(&input);
+            let printed = print_pretty(&parsed);
+            let reparsed = parse(&printed);
+            prop_assert_eq!(&reparsed, &parsed);
+            prop_assert_eq!(&print_pretty(&reparsed), &printed);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use std::process::Stdio;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::process::Child;
+use tokio::process::ChildStdin;
+use tokio::process::ChildStdout;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::sync::Semaphore;
+
+use crate::error::OrgParseError;
+use crate::parse::WORKER_SCRIPT;
+
+/// Generate a marker that will not appear in any output other than the lines we asked emacs to write.
+pub fn new_sentinel() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or(0);
+    format!(
+        "ORG-INVESTIGATION-{:x}-{:x}-{:x}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        nanos
+    )
+}
+
+pub struct WorkerPoolConfig {
+    /// Maximum number of emacs processes running at once.
+    pub size: usize,
+    /// How long a single request may take before its worker is killed. Starting a worker for the request gets the same amount of time on top.
+    pub request_timeout: Duration,
+}
+
+impl WorkerPoolConfig {
+    /// Read the pool configuration from the environment, falling back to one worker per available CPU.
+    pub fn from_env() -> Result<WorkerPoolConfig, Box<dyn std::error::Error>> {
+        let size = match std::env::var("ORG_INVESTIGATION_EMACS_WORKERS") {
+            Ok(val) => val.parse::<usize>()?,
+            Err(_) => std::thread::available_parallelism()
+                .map(|parallelism| parallelism.get())
+                .unwrap_or(1),
+        };
+        if size == 0 {
+            return Err("ORG_INVESTIGATION_EMACS_WORKERS must be at least 1.".into());
+        }
+        let request_timeout = match std::env::var("ORG_INVESTIGATION_EMACS_TIMEOUT_MS") {
+            Ok(val) => Duration::from_millis(val.parse::<u64>()?),
+            Err(_) => Duration::from_secs(10),
+        };
+        Ok(WorkerPoolConfig {
+            size,
+            request_timeout,
+        })
+    }
+}
+
+/// A pool of long-lived emacs processes running `WORKER_SCRIPT`.
+///
+/// Workers are spawned lazily (or up front by `warm_up`). A worker that fails mid-request is discarded and a fresh one is spawned the next time one is needed.
+pub struct EmacsWorkerPool {
+    config: WorkerPoolConfig,
+    permits: Semaphore,
+    idle: Mutex<Vec<EmacsWorker>>,
+}
+
+/// A single line of a worker's reply, `<sentinel> <kind> [<base64 payload>]`.
+pub struct Frame {
+    pub kind: String,
+    pub payload: Vec<u8>,
+}
+
+pub struct Reply {
+    pub frames: Vec<Frame>,
+    /// Everything else emacs printed while handling the request, one entry per line.
+    pub warnings: Vec<String>,
+}
+
+impl EmacsWorkerPool {
+    pub fn new(config: WorkerPoolConfig) -> EmacsWorkerPool {
+        let permits = Semaphore::new(config.size);
+        EmacsWorkerPool {
+            config,
+            permits,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.config.size
+    }
+
+    /// Spawn every worker in the pool so the first requests do not pay for emacs startup.
+    pub async fn warm_up(&self) -> Result<(), OrgParseError> {
+        // Start every process before waiting on any of them so they boot concurrently.
+        let workers = (0..self.config.size)
+            .map(|_| EmacsWorker::start())
+            .collect::<Result<Vec<_>, _>>()?;
+        for mut worker in workers {
+            self.within_timeout(worker.health_check()).await?;
+            self.check_in(worker);
+        }
+        Ok(())
+    }
+
+    /// Send a single request to an idle worker and collect its reply.
+    ///
+    /// `request` is the body of the request plist, for example `:op parse :document "..."`.
+    ///
+    /// If this future is dropped part-way through (for example because the client disconnected) or the request times out, the worker is dropped with it, which kills the emacs process.
+    pub async fn request(&self, request: &str) -> Result<Reply, OrgParseError> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("The pool semaphore is never closed.");
+        // Starting a replacement worker can be slow, so it is timed separately from the request rather than eating into its time. An emacs that hangs while starting is still killed instead of holding the permit forever.
+        let mut worker = self.within_timeout(self.check_out()).await?;
+        // If anything goes wrong talking to the worker we drop it rather than returning it to the pool, since its stdout may be left part-way through a reply.
+        let reply = self.within_timeout(worker.request(request)).await?;
+        self.check_in(worker);
+        Ok(reply)
+    }
+
+    /// Run `future` for at most the configured request timeout. On timeout the future is dropped along with any worker it holds, which kills the emacs process.
+    async fn within_timeout<T>(
+        &self,
+        future: impl std::future::Future<Output = Result<T, OrgParseError>>,
+    ) -> Result<T, OrgParseError> {
+        let timeout = self.config.request_timeout;
+        tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| OrgParseError::Timeout {
+                timeout_ms: timeout.as_millis(),
+            })?
+    }
+
+    async fn check_out(&self) -> Result<EmacsWorker, OrgParseError> {
+        loop {
+            let idle_worker = self.idle.lock().expect("Pool mutex poisoned.").pop();
+            match idle_worker {
+                Some(mut worker) => {
+                    if worker.is_alive() {
+                        return Ok(worker);
+                    }
+                    // The worker died while idle, throw it away and try the next one.
+                }
+                None => return EmacsWorker::spawn().await,
+            }
+        }
+    }
+
+    fn check_in(&self, worker: EmacsWorker) {
+        self.idle.lock().expect("Pool mutex poisoned.").push(worker);
+    }
+}
+
+struct EmacsWorker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    /// Lines read from stderr by a background task, so emacs never blocks on a full pipe between requests.
+    stderr: mpsc::UnboundedReceiver<String>,
+}
+
+impl EmacsWorker {
+    async fn spawn() -> Result<EmacsWorker, OrgParseError> {
+        let mut worker = EmacsWorker::start()?;
+        worker.health_check().await?;
+        Ok(worker)
+    }
+
+    fn start() -> Result<EmacsWorker, OrgParseError> {
+        let mut cmd = Command::new("emacs");
+        let proc = cmd
+            .arg("-q")
+            .arg("--no-site-file")
+            .arg("--no-splash")
+            .arg("--batch")
+            .arg("--eval")
+            .arg(WORKER_SCRIPT)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = proc.spawn().map_err(|e| OrgParseError::Spawn {
+            message: e.to_string(),
+        })?;
+        let stdin = child.stdin.take().ok_or(OrgParseError::Spawn {
+            message: "Emacs worker has no stdin.".to_owned(),
+        })?;
+        let stdout = child.stdout.take().ok_or(OrgParseError::Spawn {
+            message: "Emacs worker has no stdout.".to_owned(),
+        })?;
+        let stderr = child.stderr.take().ok_or(OrgParseError::Spawn {
+            message: "Emacs worker has no stderr.".to_owned(),
+        })?;
+        let (stderr_sender, stderr_receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut stderr = BufReader::new(stderr);
+            // Stops when emacs exits or the worker (and therefore the receiver) is dropped.
+            while let Ok(Some(line)) = read_lossy_line(&mut stderr).await {
+                if stderr_sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(EmacsWorker {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            stderr: stderr_receiver,
+        })
+    }
+
+    fn is_alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Round-trip a ping through the worker to make sure it has finished starting up and is reading requests.
+    async fn health_check(&mut self) -> Result<(), OrgParseError> {
+        let reply = self.request(":op ping").await?;
+        if !reply.frames.iter().any(|frame| frame.kind == "pong") {
+            return Err(OrgParseError::Io {
+                message: "Emacs worker did not answer the health check.".to_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn request(&mut self, request: &str) -> Result<Reply, OrgParseError> {
+        let sentinel = new_sentinel();
+        let request_line = format!(
+            "(:sentinel \"{sentinel}\" {request})\n",
+            sentinel = sentinel,
+            request = request
+        );
+        let written = async {
+            self.stdin.write_all(request_line.as_bytes()).await?;
+            self.stdin.flush().await
+        }
+        .await;
+        if written.is_err() {
+            return Err(self.exited().await);
+        }
+
+        let frame_prefix = format!("{} ", sentinel);
+        let mut frames = Vec::new();
+        let mut warnings = Vec::new();
+        loop {
+            let line = match read_lossy_line(&mut self.stdout).await {
+                Ok(Some(line)) => line,
+                Ok(None) => return Err(self.exited().await),
+                Err(e) => {
+                    return Err(OrgParseError::Io {
+                        message: e.to_string(),
+                    })
+                }
+            };
+            let frame = match line.strip_prefix(&frame_prefix) {
+                Some(frame) => frame,
+                None => {
+                    // Not part of the reply, something else in emacs wrote to stdout.
+                    warnings.push(line);
+                    continue;
+                }
+            };
+            let (kind, payload) = match frame.split_once(' ') {
+                Some((kind, payload)) => (
+                    kind,
+                    STANDARD.decode(payload).map_err(|e| OrgParseError::Io {
+                        message: e.to_string(),
+                    })?,
+                ),
+                None => (frame, Vec::new()),
+            };
+            if kind == "done" {
+                break;
+            }
+            frames.push(Frame {
+                kind: kind.to_owned(),
+                payload,
+            });
+        }
+
+        // The worker writes "<sentinel> end" to stderr before the done frame, so everything up to that line belongs to this request.
+        let begin_line = format!("{} begin", sentinel);
+        let end_line = format!("{} end", sentinel);
+        while let Some(line) = self.stderr.recv().await {
+            if line == end_line {
+                break;
+            }
+            if line != begin_line {
+                warnings.push(line);
+            }
+        }
+        Ok(Reply { frames, warnings })
+    }
+
+    /// Describe why the worker stopped talking to us once its pipes have closed.
+    async fn exited(&mut self) -> OrgParseError {
+        match self.child.wait().await {
+            Ok(status) => OrgParseError::NonZeroExit {
+                status: status.code(),
+            },
+            Err(e) => OrgParseError::Io {
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+/// Read one line without its line ending, replacing invalid UTF-8. Returns `None` at end of file.
+async fn read_lossy_line<R>(reader: &mut R) -> std::io::Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut buffer = Vec::new();
+    if reader.read_until(b'\n', &mut buffer).await? == 0 {
+        return Ok(None);
+    }
+    if buffer.ends_with(b"\n") {
+        buffer.pop();
+    }
+    if buffer.ends_with(b"\r") {
+        buffer.pop();
+    }
+    Ok(Some(String::from_utf8_lossy(&buffer).into_owned()))
+}
@@ -2,6 +2,8 @@ let inFlightRequest = null;
 const inputElement = document.querySelector("#org-input");
 const outputElement = document.querySelector("#parse-output");
 const astTreeElement = document.querySelector("#ast-tree");
+const propertyInspectorElement = document.querySelector("#property-inspector");
+const exportBackendsElement = document.querySelector("#export-backends");
 
 function abortableFetch(request, options) {
     const controller = new AbortController();
@@ -22,7 +24,109 @@ function clearOutput() {
 function renderParseResponse(response) {
     clearOutput();
     renderSourceBox(response);
+    renderWarnings(response);
+    renderLint(response);
+    renderPlainListOwnership(response);
     renderAstTree(response);
+    renderExports(response);
+}
+
+function renderExports(response) {
+    for (let exported of response.exports) {
+        const exportElem = document.createElement("div");
+        exportElem.classList.add("export");
+        const headerElem = document.createElement("h3");
+        headerElem.innerText = `Exported with ${exported.backend}:`;
+        exportElem.appendChild(headerElem);
+        const bodyElem = document.createElement("pre");
+        if (exported.error !== undefined) {
+            bodyElem.classList.add("parse_error");
+            bodyElem.innerText = exported.error;
+        } else {
+            bodyElem.classList.add("code_block");
+            bodyElem.innerText = exported.output;
+        }
+        exportElem.appendChild(bodyElem);
+        astTreeElement.appendChild(exportElem);
+    }
+}
+
+function selectedExportBackends() {
+    return Array.from(exportBackendsElement.querySelectorAll("input:checked"), (checkbox) => checkbox.value);
+}
+
+function renderLint(response) {
+    for (let diagnostic of response.lint) {
+        const diagnosticElem = document.createElement("div");
+        // Shares the ast_node class so clicking it highlights the source the same way.
+        diagnosticElem.classList.add("ast_node", "lint_diagnostic", `lint_trust_${diagnostic.trust}`);
+        diagnosticElem.innerText = `${diagnostic.checker} (line ${diagnostic.position.start_line}): ${diagnostic.message}`;
+        diagnosticElem.dataset.startLine = diagnostic.position.start_line;
+        diagnosticElem.dataset.endLine = diagnostic.position.end_line;
+        diagnosticElem.dataset.startCharacter = diagnostic.position.start_character;
+        diagnosticElem.dataset.endCharacter = diagnostic.position.end_character;
+        diagnosticElem.addEventListener("click", () => {
+            setActiveAstNode(diagnosticElem, response.input);
+        });
+        astTreeElement.appendChild(diagnosticElem);
+    }
+}
+
+// Printed as an org table so it can be pasted into notes/plain_list_ownership_notes.org.
+function renderPlainListOwnership(response) {
+    const rows = [];
+    const collectItems = (lists) => {
+        for (let list of lists) {
+            for (let item of list.items) {
+                rows.push([item.label, item.owns_trailing_blank_lines ? "Yes" : "No"]);
+                collectItems(item.lists);
+            }
+        }
+    };
+    collectItems(response.plain_lists);
+    if (rows.length === 0) {
+        return;
+    }
+    const header = ["Plain List *Item*", "Owns trailing blank lines"];
+    const widths = header.map((title, column) => Math.max(title.length, ...rows.map((row) => row[column].length)));
+    const formatRow = (row) => `| ${row.map((cell, column) => cell.padEnd(widths[column])).join(" | ")} |`;
+    const separator = `|${widths.map((width) => "-".repeat(width + 2)).join("+")}|`;
+
+    const ownershipElem = document.createElement("div");
+    ownershipElem.classList.add("plain_list_ownership");
+    const headerElem = document.createElement("h3");
+    headerElem.innerText = "Plain list ownership:";
+    ownershipElem.appendChild(headerElem);
+    const tableElem = document.createElement("pre");
+    tableElem.classList.add("code_block");
+    tableElem.innerText = [formatRow(header), separator, ...rows.map(formatRow)].join("\n");
+    ownershipElem.appendChild(tableElem);
+    astTreeElement.appendChild(ownershipElem);
+}
+
+function renderWarnings(response) {
+    for (let warning of response.warnings) {
+        const warningElem = document.createElement("div");
+        warningElem.classList.add("parse_warning");
+        warningElem.innerText = warning;
+        astTreeElement.appendChild(warningElem);
+    }
+}
+
+function renderErrorResponse(error) {
+    clearOutput();
+    const errorElem = document.createElement("div");
+    errorElem.classList.add("parse_error");
+    // The error body is a JSON object tagged with its "kind", show all of it since the fields differ per kind.
+    errorElem.innerText = JSON.stringify(error, null, 2);
+    astTreeElement.appendChild(errorElem);
+    if (error.excerpt !== undefined) {
+        // Sexp errors point into the AST, which is easier to read with the caret lined up under the problem.
+        const excerptElem = document.createElement("pre");
+        excerptElem.classList.add("parse_error");
+        excerptElem.innerText = error.excerpt;
+        astTreeElement.appendChild(excerptElem);
+    }
 }
 
 function renderSourceBox(response) {
@@ -63,14 +167,24 @@ function renderAstNode(originalSource, depth, astNode) {
     let escapedSource = JSON.stringify(sourceForNode);
 
     nodeElem.innerText = `${astNode.name}: ${escapedSource}`;
+    if (astNode.contents_unparsed) {
+        nodeElem.classList.add("contents_unparsed");
+    }
     nodeElem.style.marginLeft = `${depth * 20}px`;
     nodeElem.dataset.startLine = astNode.position.start_line;
     nodeElem.dataset.endLine = astNode.position.end_line;
     nodeElem.dataset.startCharacter = astNode.position.start_character;
     nodeElem.dataset.endCharacter = astNode.position.end_character;
 
+    const regions = [
+        { className: "region_affiliated", range: astNode.affiliated },
+        { className: "region_contents", range: astNode.contents },
+        { className: "region_trailing_blank", range: astNode.trailing_blank },
+    ].filter((region) => region.range !== null);
+
     nodeElem.addEventListener("click", () => {
-        setActiveAstNode(nodeElem, originalSource);
+        setActiveAstNode(nodeElem, originalSource, regions);
+        renderPropertyInspector(astNode);
     });
 
     astTreeElement.appendChild(nodeElem);
@@ -79,7 +193,83 @@ function renderAstNode(originalSource, depth, astNode) {
     }
 }
 
+function renderPropertyInspector(astNode) {
+    propertyInspectorElement.innerHTML = "";
+    const headerElem = document.createElement("h3");
+    headerElem.innerText = `Properties of ${astNode.name}:`;
+    propertyInspectorElement.appendChild(headerElem);
+    if (astNode.properties.length === 0) {
+        const emptyElem = document.createElement("div");
+        emptyElem.innerText = "No properties besides its position.";
+        propertyInspectorElement.appendChild(emptyElem);
+        return;
+    }
+    const tableElem = document.createElement("table");
+    for (let property of astNode.properties) {
+        const rowElem = document.createElement("tr");
+        const keyElem = document.createElement("th");
+        // Plain text properties are given per run of text, so the same key can appear once for each run.
+        keyElem.innerText = property.run === null
+            ? property.key
+            : `${property.key} (${property.run.start_character}..${property.run.end_character})`;
+        rowElem.appendChild(keyElem);
+        const valueElem = document.createElement("td");
+        valueElem.innerText = formatSexpValue(property.value);
+        rowElem.appendChild(valueElem);
+        tableElem.appendChild(rowElem);
+    }
+    propertyInspectorElement.appendChild(tableElem);
+}
+
+// Print a value from sexp_json::token_to_json back as elisp.
+function formatSexpValue(value) {
+    const formatAll = (values) => values.map(formatSexpValue).join(" ");
+    switch (value.type) {
+        case "nil":
+            return "nil";
+        case "symbol":
+            return value.name;
+        case "string":
+        case "text":
+            return JSON.stringify(value.value);
+        case "integer":
+        case "float":
+            // Bignums keep their printed source.
+            return value.source ?? `${value.value}`;
+        case "character":
+        case "unreadable":
+            return value.source;
+        case "list":
+            if (value.tail !== undefined) {
+                return `(${formatAll(value.elements)} . ${formatSexpValue(value.tail)})`;
+            }
+            return `(${formatAll(value.elements)})`;
+        case "plist":
+            return `(${value.properties.map((property) => `${property.key} ${formatSexpValue(property.value)}`).join(" ")})`;
+        case "vector":
+            return `[${formatAll(value.elements)}]`;
+        case "record":
+            return `#s(${formatAll(value.elements)})`;
+        case "byte-code":
+            return `#[${formatAll(value.elements)}]`;
+        case "bool-vector":
+            return `#&${value.length}${JSON.stringify(value.bits)}`;
+        case "quote": {
+            const prefixes = { quote: "'", backquote: "`", unquote: ",", splice: ",@", function: "#'" };
+            return `${prefixes[value.macro]}${formatSexpValue(value.value)}`;
+        }
+        case "labeled":
+            return formatSexpValue(value.value);
+        case "reference":
+            return `#${value.label}#`;
+        case "level-reference":
+            return `#${value.levels}`;
+    }
+    return JSON.stringify(value);
+}
+
 function clearActiveAstNode() {
+    propertyInspectorElement.innerHTML = "";
     for (let elem of document.querySelectorAll("#ast-tree .ast_node.highlighted")) {
         elem.classList.remove("highlighted");
     }
@@ -87,11 +277,14 @@ function clearActiveAstNode() {
         elem.classList.remove("highlighted");
     }
     for (let elem of document.querySelectorAll("#parse-output > code > span")) {
-        elem.classList.remove("highlighted");
+        elem.classList.remove("highlighted", ...regionClassNames);
     }
 }
 
-function setActiveAstNode(elem, originalSource) {
+const regionClassNames = ["region_affiliated", "region_contents", "region_trailing_blank"];
+
+// Regions are drawn over the node's own highlighting, so whatever is left in the plain highlight colour is owned by the node itself rather than its children.
+function setActiveAstNode(elem, originalSource, regions = []) {
     clearActiveAstNode();
     elem.classList.add("highlighted");
     let startLine = parseInt(elem.dataset.startLine, 10);
@@ -102,9 +295,12 @@ function setActiveAstNode(elem, originalSource) {
         highlightLine("parse-output", line - 1);
     }
     highlightCharacters("parse-output", originalSource, startCharacter, endCharacter);
+    for (let region of regions) {
+        highlightCharacters("parse-output", originalSource, region.range.start_character, region.range.end_character, region.className);
+    }
 }
 
-inputElement.addEventListener("input", async () => {
+async function parseInput() {
     let orgSource = inputElement.value;
     if (inFlightRequest != null) {
         inFlightRequest.abort();
@@ -115,7 +311,13 @@ inputElement.addEventListener("input", async () => {
     let newRequest = abortableFetch("/parse", {
         method: "POST",
         cache: "no-cache",
-        body: orgSource,
+        headers: {
+            "Content-Type": "application/json",
+        },
+        body: JSON.stringify({
+            input: orgSource,
+            export_backends: selectedExportBackends(),
+        }),
     });
     inFlightRequest = newRequest;
 
@@ -126,8 +328,15 @@ inputElement.addEventListener("input", async () => {
     catch (err) {
         if (err.name === "AbortError") return;
     }
+    if (!response.ok) {
+        renderErrorResponse(await response.json());
+        return;
+    }
     renderParseResponse(await response.json());
-});
+}
+
+inputElement.addEventListener("input", parseInput);
+exportBackendsElement.addEventListener("change", parseInput);
 
 function highlightLine(htmlName, lineOffset) {
   const childOffset = lineOffset + 1;
@@ -135,14 +344,14 @@ function highlightLine(htmlName, lineOffset) {
   codeLineElement?.classList.add("highlighted")
 }
 
-function highlightCharacters(htmlName, originalSource, startCharacter, endCharacter) {
+function highlightCharacters(htmlName, originalSource, startCharacter, endCharacter, className = "highlighted") {
     let sourceBefore = unicodeAwareSlice(originalSource, 0, startCharacter - 1);
     let precedingLineBreak = unicodeAwareLastIndexOfCharacter(sourceBefore, "\n");
     let characterIndexOnLine = precedingLineBreak !== -1 ? startCharacter - precedingLineBreak - 1 : startCharacter;
     let lineNumber = (sourceBefore.match(/\r?\n/g) || '').length + 1;
 
     for (let characterIndex = startCharacter; characterIndex < endCharacter; ++characterIndex) {
-        document.querySelector(`#${htmlName} > code:nth-child(${lineNumber}) > span:nth-child(${characterIndexOnLine})`)?.classList.add("highlighted");
+        document.querySelector(`#${htmlName} > code:nth-child(${lineNumber}) > span:nth-child(${characterIndexOnLine})`)?.classList.add(className);
         if (unicodeAwareCharAtOffset(originalSource, characterIndex - 1) == "\n") {
             ++lineNumber;
             characterIndexOnLine = 1;